@@ -166,10 +166,7 @@ fn build_sse2_sse41_avx2_assembly() {
     build.compile("blake3_sse2_sse41_avx2_assembly");
 }
 
-fn build_avx512_c_intrinsics() {
-    // This is required on 32-bit x86 targets, since the assembly
-    // implementation doesn't support those.
-    println!("cargo:rustc-cfg=blake3_avx512_ffi");
+fn new_avx512_c_intrinsics_build() -> cc::Build {
     let mut build = new_build();
     build.file("c/blake3_avx512.c");
     if is_windows_msvc() {
@@ -182,7 +179,14 @@ fn build_avx512_c_intrinsics() {
         // Workaround for https://gcc.gnu.org/bugzilla/show_bug.cgi?id=65782.
         build.flag("-fno-asynchronous-unwind-tables");
     }
-    build.compile("blake3_avx512_intrinsics");
+    build
+}
+
+fn build_avx512_c_intrinsics() {
+    // This is required on 32-bit x86 targets, since the assembly
+    // implementation doesn't support those.
+    println!("cargo:rustc-cfg=blake3_avx512_ffi");
+    new_avx512_c_intrinsics_build().compile("blake3_avx512_intrinsics");
 }
 
 fn build_avx512_assembly() {
@@ -208,6 +212,20 @@ fn build_avx512_assembly() {
         build.flag("-mavx512vl");
     }
     build.compile("blake3_avx512_assembly");
+
+    // The assembly implementation doesn't have a multi-block XOF kernel, so
+    // take blake3_xof_many_avx512 from the C intrinsics implementation. Rename
+    // the C versions of the functions that the assembly already defines, so
+    // that the symbols don't collide. Those copies go unused.
+    let mut build = new_avx512_c_intrinsics_build();
+    for name in [
+        "blake3_compress_in_place_avx512",
+        "blake3_compress_xof_avx512",
+        "blake3_hash_many_avx512",
+    ] {
+        build.define(name, Some(&*format!("{}_unused", name)));
+    }
+    build.compile("blake3_avx512_xof_intrinsics");
 }
 
 fn build_neon_c_intrinsics() {
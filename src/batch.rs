@@ -0,0 +1,393 @@
+//! Hashing many independent messages at once.
+//!
+//! `Platform::hash_many` compresses several inputs in parallel, but every
+//! input has to be the same whole number of blocks, and every input starts
+//! from the same key. Short messages hashed with the same key satisfy the
+//! second condition, so here we sort them into buckets by length, and hand
+//! each full bucket to `hash_many`. A message whose last block isn't full has
+//! that block compressed separately afterwards, because `hash_many` only
+//! supports full blocks. Messages longer than one chunk are hashed one at a
+//! time, with the same SIMD tree hashing that [`hash`](crate::hash) uses.
+
+use crate::platform::{Platform, MAX_SIMD_DEGREE};
+use crate::{
+    hash_all_at_once, join, platform, CVWords, ChunkState, Hash, IncrementCounter, BLOCK_LEN,
+    CHUNK_END, CHUNK_LEN, CHUNK_START, DERIVE_KEY_MATERIAL, IV, KEYED_HASH, KEY_LEN, OUT_LEN, ROOT,
+};
+use arrayref::array_ref;
+use arrayvec::ArrayVec;
+
+// The number of different block counts that a message of one chunk or less
+// can have, not counting the empty message.
+const MAX_BLOCKS: usize = CHUNK_LEN / BLOCK_LEN;
+
+/// Hash many independent messages, and write each [`hash`](crate::hash) to
+/// the corresponding element of `out`.
+///
+/// Messages of up to 1024 bytes are spread across SIMD lanes, several
+/// messages at a time, which is much faster than hashing them one by one on
+/// platforms with SIMD support. Messages of similar lengths batch together
+/// best, and a message whose length is a multiple of 64 bytes is hashed
+/// entirely in parallel with others of the same length. Longer messages are
+/// hashed one at a time.
+///
+/// This function is always single-threaded. For multithreading support, see
+/// [`hash_many_rayon`](fn.hash_many_rayon.html).
+///
+/// # Panics
+///
+/// Panics if `inputs` and `out` have different lengths.
+///
+/// # Example
+///
+/// ```
+/// let inputs: [&[u8]; 3] = [b"foo", b"bar", &[0; 1000]];
+/// let mut hashes = [blake3::Hash::from([0; 32]); 3];
+/// blake3::hash_many(&inputs, &mut hashes);
+/// for (input, hash) in inputs.iter().zip(&hashes) {
+///     assert_eq!(*hash, blake3::hash(input));
+/// }
+/// ```
+pub fn hash_many(inputs: &[&[u8]], out: &mut [Hash]) {
+    hash_many_serial(inputs, out, IV, 0);
+}
+
+/// As [`hash_many`], but with the [keyed hash function](crate::keyed_hash).
+pub fn keyed_hash_many(key: &[u8; KEY_LEN], inputs: &[&[u8]], out: &mut [Hash]) {
+    let key_words = platform::words_from_le_bytes_32(key);
+    hash_many_serial(inputs, out, &key_words, KEYED_HASH);
+}
+
+/// As [`hash_many`], but with the [key derivation function](crate::derive_key).
+/// Every derived key uses the same context string. To hash the context string
+/// only once for many batches, see
+/// [`DeriveKeyContext::derive_key_many`](crate::DeriveKeyContext::derive_key_many).
+pub fn derive_key_many(context: &str, key_materials: &[&[u8]], out: &mut [[u8; OUT_LEN]]) {
+    let context_key_words = crate::derive_key_context_key(context.as_bytes());
+    hash_many_serial(key_materials, out, &context_key_words, DERIVE_KEY_MATERIAL);
+}
+
+/// Identical to [`hash_many`], but using Rayon-based multithreading to split
+/// the messages across threads.
+///
+/// This function is gated by the `rayon` Cargo feature, which is disabled by
+/// default but enabled on [docs.rs](https://docs.rs). It's only worth using
+/// for large batches, and it's important to benchmark your specific use case.
+#[cfg(feature = "rayon")]
+pub fn hash_many_rayon(inputs: &[&[u8]], out: &mut [Hash]) {
+    hash_many_with_join::<join::RayonJoin, _>(inputs, out, IV, 0);
+}
+
+/// Identical to [`keyed_hash_many`], but using Rayon-based multithreading.
+/// See [`hash_many_rayon`].
+#[cfg(feature = "rayon")]
+pub fn keyed_hash_many_rayon(key: &[u8; KEY_LEN], inputs: &[&[u8]], out: &mut [Hash]) {
+    let key_words = platform::words_from_le_bytes_32(key);
+    hash_many_with_join::<join::RayonJoin, _>(inputs, out, &key_words, KEYED_HASH);
+}
+
+/// Identical to [`derive_key_many`], but using Rayon-based multithreading.
+/// See [`hash_many_rayon`].
+#[cfg(feature = "rayon")]
+pub fn derive_key_many_rayon(context: &str, key_materials: &[&[u8]], out: &mut [[u8; OUT_LEN]]) {
+    let context_key_words = crate::derive_key_context_key(context.as_bytes());
+    hash_many_with_join::<join::RayonJoin, _>(
+        key_materials,
+        out,
+        &context_key_words,
+        DERIVE_KEY_MATERIAL,
+    );
+}
+
+pub(crate) fn hash_many_serial<T: From<Hash>>(
+    inputs: &[&[u8]],
+    out: &mut [T],
+    key: &CVWords,
+    flags: u8,
+) {
+    assert_eq!(
+        inputs.len(),
+        out.len(),
+        "inputs and out must be the same length"
+    );
+    hash_batch(inputs, out, key, flags, Platform::detect());
+}
+
+#[cfg(feature = "rayon")]
+pub(crate) fn hash_many_with_join<J: join::Join, T: From<Hash> + Send>(
+    inputs: &[&[u8]],
+    out: &mut [T],
+    key: &CVWords,
+    flags: u8,
+) {
+    assert_eq!(
+        inputs.len(),
+        out.len(),
+        "inputs and out must be the same length"
+    );
+    split_with_join::<J, T>(inputs, out, key, flags, Platform::detect());
+}
+
+// Split the batch in half recursively, to spread it across threads.
+#[cfg(feature = "rayon")]
+fn split_with_join<J: join::Join, T: From<Hash> + Send>(
+    inputs: &[&[u8]],
+    out: &mut [T],
+    key: &CVWords,
+    flags: u8,
+    platform: Platform,
+) {
+    // Below this many messages, the cost of splitting outweighs the benefit,
+    // and smaller batches fill fewer buckets.
+    const MIN_SPLIT_LEN: usize = 1024;
+    if inputs.len() <= MIN_SPLIT_LEN {
+        hash_batch(inputs, out, key, flags, platform);
+        return;
+    }
+    let mid = inputs.len() / 2;
+    let (left_inputs, right_inputs) = inputs.split_at(mid);
+    let (left_out, right_out) = out.split_at_mut(mid);
+    J::join(
+        || split_with_join::<J, T>(left_inputs, left_out, key, flags, platform),
+        || split_with_join::<J, T>(right_inputs, right_out, key, flags, platform),
+    );
+}
+
+fn hash_batch<T: From<Hash>>(
+    inputs: &[&[u8]],
+    out: &mut [T],
+    key: &CVWords,
+    flags: u8,
+    platform: Platform,
+) {
+    // Messages waiting for a full SIMD batch, indexed by their number of
+    // blocks and by whether their last block is full.
+    let mut buckets: [[ArrayVec<usize, MAX_SIMD_DEGREE>; 2]; MAX_BLOCKS] = Default::default();
+    for (i, input) in inputs.iter().enumerate() {
+        if input.len() > CHUNK_LEN {
+            out[i] = hash_all_at_once::<join::SerialJoin>(input, key, flags)
+                .root_hash()
+                .into();
+            continue;
+        }
+        if input.len() < BLOCK_LEN {
+            // A single partial block, with nothing to parallelize.
+            out[i] = finish_chunk(key, 0, input, flags, platform).into();
+            continue;
+        }
+        let blocks = (input.len() - 1) / BLOCK_LEN + 1;
+        let last_block_full = input.len() & (BLOCK_LEN - 1) == 0;
+        let bucket = &mut buckets[blocks - 1][last_block_full as usize];
+        bucket.push(i);
+        if bucket.is_full() {
+            hash_bucket(
+                inputs,
+                out,
+                bucket,
+                blocks,
+                last_block_full,
+                key,
+                flags,
+                platform,
+            );
+            bucket.clear();
+        }
+    }
+    for (blocks_minus_one, pair) in buckets.iter().enumerate() {
+        for (last_block_full, bucket) in pair.iter().enumerate() {
+            if !bucket.is_empty() {
+                let blocks = blocks_minus_one + 1;
+                let last_block_full = last_block_full == 1;
+                hash_bucket(
+                    inputs,
+                    out,
+                    bucket,
+                    blocks,
+                    last_block_full,
+                    key,
+                    flags,
+                    platform,
+                );
+            }
+        }
+    }
+}
+
+// Compress the remaining input of a chunk, starting from the CV after
+// `blocks_compressed` blocks, and return the root hash.
+fn finish_chunk(
+    cv: &CVWords,
+    blocks_compressed: usize,
+    input: &[u8],
+    flags: u8,
+    platform: Platform,
+) -> Hash {
+    let mut chunk_state = ChunkState::new(cv, 0, flags, platform);
+    chunk_state.blocks_compressed = blocks_compressed as u8;
+    chunk_state.update(input).output().root_hash()
+}
+
+// Hash a bucket of messages that all have the same number of blocks, and
+// whose last blocks are either all full or all partial.
+#[allow(clippy::too_many_arguments)]
+fn hash_bucket<T: From<Hash>>(
+    inputs: &[&[u8]],
+    out: &mut [T],
+    indexes: &[usize],
+    blocks: usize,
+    last_block_full: bool,
+    key: &CVWords,
+    flags: u8,
+    platform: Platform,
+) {
+    debug_assert!(blocks > 1 || last_block_full);
+    // If the last blocks are full, they're compressed in parallel too, as the
+    // root. Otherwise they're finished one at a time below.
+    let (parallel_blocks, flags_end) = if last_block_full {
+        (blocks, CHUNK_END | ROOT)
+    } else {
+        (blocks - 1, 0)
+    };
+    let mut cvs = [0; MAX_SIMD_DEGREE * OUT_LEN];
+    let args = (inputs, indexes, key, flags, flags_end, &mut cvs[..]);
+    // Platform::hash_many takes the input length as a const parameter.
+    macro_rules! dispatch {
+        ($($n:literal)*) => {
+            match parallel_blocks {
+                $($n => hash_prefixes::<{ $n * BLOCK_LEN }>(platform, args),)*
+                _ => unreachable!(),
+            }
+        };
+    }
+    dispatch!(1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16);
+
+    for (&i, cv) in indexes.iter().zip(cvs.chunks_exact(OUT_LEN)) {
+        let cv = *array_ref!(cv, 0, OUT_LEN);
+        let hash = if last_block_full {
+            Hash::from(cv)
+        } else {
+            let cv_words = platform::words_from_le_bytes_32(&cv);
+            let rest = &inputs[i][parallel_blocks * BLOCK_LEN..];
+            finish_chunk(&cv_words, parallel_blocks, rest, flags, platform)
+        };
+        out[i] = hash.into();
+    }
+}
+
+type PrefixArgs<'a> = (
+    &'a [&'a [u8]],
+    &'a [usize],
+    &'a CVWords,
+    u8,
+    u8,
+    &'a mut [u8],
+);
+
+fn hash_prefixes<const N: usize>(platform: Platform, args: PrefixArgs) {
+    let (inputs, indexes, key, flags, flags_end, out) = args;
+    let prefixes: ArrayVec<&[u8; N], MAX_SIMD_DEGREE> = indexes
+        .iter()
+        .map(|&i| <&[u8; N]>::try_from(&inputs[i][..N]).unwrap())
+        .collect();
+    platform.hash_many(
+        &prefixes,
+        key,
+        0,
+        IncrementCounter::No,
+        flags,
+        CHUNK_START,
+        flags_end,
+        out,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{paint_test_input, TEST_KEY};
+
+    const NUM_INPUTS: usize = CHUNK_LEN + 2 * BLOCK_LEN;
+
+    // Every length up to a bit more than a chunk, in an order that mixes the
+    // buckets up, and with some lengths repeated more than any SIMD degree.
+    fn test_inputs(buf: &[u8]) -> ArrayVec<&[u8], NUM_INPUTS> {
+        (0..NUM_INPUTS)
+            .map(|i| {
+                let len = (i * 37) % NUM_INPUTS;
+                let len = if i & 3 == 0 {
+                    len & !(BLOCK_LEN - 1)
+                } else {
+                    len
+                };
+                &buf[i..][..len]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_hash_many() {
+        let mut buf = [0; 2 * NUM_INPUTS];
+        paint_test_input(&mut buf);
+        let inputs = test_inputs(&buf);
+
+        let mut hashes = [Hash::from([0; OUT_LEN]); NUM_INPUTS];
+        hash_many(&inputs, &mut hashes);
+        for (input, hash) in inputs.iter().zip(&hashes) {
+            assert_eq!(*hash, crate::hash(input));
+        }
+
+        keyed_hash_many(&TEST_KEY, &inputs, &mut hashes);
+        for (input, hash) in inputs.iter().zip(&hashes) {
+            assert_eq!(*hash, crate::keyed_hash(&TEST_KEY, input));
+        }
+
+        let context = "BLAKE3 2023-08-01 batch test context";
+        let mut keys = [[0; OUT_LEN]; NUM_INPUTS];
+        derive_key_many(context, &inputs, &mut keys);
+        for (input, key) in inputs.iter().zip(&keys) {
+            assert_eq!(*key, crate::derive_key(context, input));
+        }
+
+        // Empty batches are fine.
+        hash_many(&[], &mut []);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_hash_many_length_mismatch() {
+        hash_many(&[b"foo"], &mut []);
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn test_hash_many_rayon() {
+        let mut buf = [0; 2 * NUM_INPUTS];
+        paint_test_input(&mut buf);
+        let inputs = test_inputs(&buf);
+        // Enough messages to split a few times.
+        let inputs: Vec<&[u8]> = inputs
+            .iter()
+            .cycle()
+            .take(5 * NUM_INPUTS)
+            .copied()
+            .collect();
+
+        let mut hashes = vec![Hash::from([0; OUT_LEN]); inputs.len()];
+        hash_many_rayon(&inputs, &mut hashes);
+        for (input, hash) in inputs.iter().zip(&hashes) {
+            assert_eq!(*hash, crate::hash(input));
+        }
+
+        keyed_hash_many_rayon(&TEST_KEY, &inputs, &mut hashes);
+        for (input, hash) in inputs.iter().zip(&hashes) {
+            assert_eq!(*hash, crate::keyed_hash(&TEST_KEY, input));
+        }
+
+        let context = "BLAKE3 2023-08-01 batch test context";
+        let mut keys = vec![[0; OUT_LEN]; inputs.len()];
+        derive_key_many_rayon(context, &inputs, &mut keys);
+        for (input, key) in inputs.iter().zip(&keys) {
+            assert_eq!(*key, crate::derive_key(context, input));
+        }
+    }
+}
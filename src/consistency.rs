@@ -0,0 +1,478 @@
+//! Consistency proofs for append-only content, like a transparency log.
+//!
+//! If some content only ever grows by appending, then the hash of any earlier
+//! version is the hash of a prefix of the current version. A
+//! [`ConsistencyProof`] shows that without revealing the content: given the
+//! hash of the content at length N and the hash of the content at length M,
+//! it proves that the first is the hash of the first N bytes of the second.
+//!
+//! The proof is made of chaining values from the BLAKE3 tree. The first N
+//! bytes of the content are covered by complete subtrees, which are also
+//! subtrees of the longer tree, and the rest of the content is covered by
+//! more complete subtrees of the longer tree. The verifier feeds those
+//! chaining values into a [`Hasher`], exactly as if it had hashed those
+//! subtrees itself, and checks that both roots come out right. A proof has
+//! at most [`MAX_CVS`] = 108 chaining values, even for the longest possible
+//! content: two for each of the 54 levels of subtrees above the chunks.
+//!
+//! There's one exception to not revealing the content. If N isn't a
+//! multiple of the 1024-byte chunk length, or if it's 1024 or less, the chunk
+//! that contains byte N is split between the two versions, and its
+//! chaining value in the shorter tree is unrelated to its chaining value in
+//! the longer tree. In that case the proof includes the bytes of that chunk,
+//! at most 1024 of them.
+//!
+//! [`prove`] reads the chaining values it needs from a [`HashTree`], which
+//! keeps the tree of the current content up to date as it grows.
+//!
+//! This module is gated by the `std` Cargo feature, which is enabled by
+//! default.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), blake3::consistency::ConsistencyError> {
+//! use blake3::consistency::{prove, ConsistencyProof};
+//! use blake3::subtree::Mode;
+//!
+//! let mut log = b"first entry\n".repeat(1000);
+//! let old_hash = blake3::hash(&log);
+//! let old_len = log.len() as u64;
+//!
+//! // Append some entries, keeping a HashTree up to date.
+//! let mut tree = blake3::HashTree::new(&log);
+//! log.extend_from_slice(&b"second entry\n".repeat(1000));
+//! tree.resize(&log);
+//! let new_hash = tree.finalize();
+//!
+//! // The log server sends the proof as bytes, and an auditor checks it.
+//! let proof_bytes = prove(&tree, &log, old_len).to_bytes();
+//! let proof = ConsistencyProof::from_bytes(&proof_bytes)?;
+//! assert_eq!(proof.old_len(), old_len);
+//! proof.verify(&Mode::hash(), &old_hash, &new_hash)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::encode::chunk_count;
+use crate::subtree::Mode;
+use crate::{CVBytes, ChunkState, Hash, HashTree, Hasher, CHUNK_LEN, MAX_DEPTH, OUT_LEN};
+use arrayref::array_ref;
+use core::cmp;
+use core::fmt;
+
+/// The maximum number of chaining values in a [`ConsistencyProof`].
+///
+/// Content of at most 2<sup>64</sup> - 1 bytes has at most 2<sup>54</sup>
+/// chunks. The subtrees on the left side of a proof are the 1 bits of a chunk
+/// count, and the subtrees on the right side grow and then shrink, so across
+/// both sides no more than two subtrees come from any one of the 54 levels.
+pub const MAX_CVS: usize = 2 * MAX_DEPTH;
+
+// A complete subtree, as (first chunk, number of chunks).
+type Subtree = (u64, u64);
+
+// The parts of a proof for a given pair of lengths, in order.
+struct Layout {
+    // Complete subtrees covering the start of the old content.
+    left: Vec<Subtree>,
+    // The chunk containing the end of the old content, as (index, length in
+    // the new content), if it's split between the two versions.
+    split_chunk: Option<(u64, usize)>,
+    // Complete subtrees of the new tree covering the rest of the new content.
+    right: Vec<Subtree>,
+}
+
+impl Layout {
+    fn new(old_len: u64, new_len: u64) -> Self {
+        debug_assert!(old_len <= new_len);
+        let old_chunks = chunk_count(old_len);
+        let new_chunks = chunk_count(new_len);
+        // A single chunk is the root of its tree, so the old tree needs the
+        // bytes of its last chunk in that case, even if it's complete.
+        let split = old_len & (CHUNK_LEN as u64 - 1) != 0 || old_chunks == 1;
+        let left_chunks = if split { old_chunks - 1 } else { old_chunks };
+
+        // The subtrees of the left side are the 1 bits of its chunk count,
+        // like the CV stack of a Hasher.
+        let mut left = Vec::new();
+        for level in (0..u64::BITS).rev() {
+            let size = 1 << level;
+            if left_chunks & size != 0 {
+                let start = left_chunks & !(2 * size - 1);
+                left.push((start, size));
+            }
+        }
+        // If that's a single subtree, it's the root of the old tree, and the
+        // verifier needs its children instead.
+        if !split && left.len() == 1 {
+            let half = left_chunks / 2;
+            left = vec![(0, half), (half, half)];
+        }
+
+        let split_chunk = if split {
+            let index = old_chunks - 1;
+            let len = cmp::min(new_len - index * CHUNK_LEN as u64, CHUNK_LEN as u64);
+            Some((index, len as usize))
+        } else {
+            None
+        };
+
+        // On the right side, take the largest subtree that's aligned at each
+        // position and fits in the new content.
+        let mut right = Vec::new();
+        let mut start = old_chunks;
+        while start < new_chunks {
+            let mut size = 1 << start.trailing_zeros();
+            while start + size > new_chunks {
+                size /= 2;
+            }
+            right.push((start, size));
+            start += size;
+        }
+        Self {
+            left,
+            split_chunk,
+            right,
+        }
+    }
+
+    fn num_cvs(&self) -> usize {
+        self.left.len() + self.right.len()
+    }
+
+    fn split_chunk_len(&self) -> usize {
+        match self.split_chunk {
+            Some((_, len)) => len,
+            None => 0,
+        }
+    }
+}
+
+/// A proof that one hash is the hash of a prefix of the content behind
+/// another hash. See the [module level docs](self).
+#[derive(Clone, Debug)]
+pub struct ConsistencyProof {
+    old_len: u64,
+    new_len: u64,
+    split_chunk: Vec<u8>,
+    cvs: Vec<CVBytes>,
+}
+
+/// Produce a [`ConsistencyProof`] that the first `old_len` bytes of the
+/// content are a prefix of the current content. `tree` must be up to date
+/// with `content`.
+///
+/// # Panics
+///
+/// Panics if the length of `content` doesn't match `tree`, or if `old_len` is
+/// greater than the length of `content`.
+pub fn prove(tree: &HashTree, content: &[u8], old_len: u64) -> ConsistencyProof {
+    let new_len = tree.content_len();
+    assert_eq!(content.len() as u64, new_len, "content doesn't match tree");
+    assert!(old_len <= new_len, "old length past the end");
+    let layout = Layout::new(old_len, new_len);
+    let cvs = layout
+        .left
+        .iter()
+        .chain(&layout.right)
+        .map(|&(start, size)| {
+            let level = size.trailing_zeros() as usize;
+            *tree.cv(level, start >> level)
+        })
+        .collect();
+    let split_chunk = match layout.split_chunk {
+        Some((index, len)) => content[index as usize * CHUNK_LEN..][..len].to_vec(),
+        None => Vec::new(),
+    };
+    ConsistencyProof {
+        old_len,
+        new_len,
+        split_chunk,
+        cvs,
+    }
+}
+
+impl ConsistencyProof {
+    /// The length of the old content, which the proof shows is a prefix.
+    pub fn old_len(&self) -> u64 {
+        self.old_len
+    }
+
+    /// The length of the new content.
+    pub fn new_len(&self) -> u64 {
+        self.new_len
+    }
+
+    /// Check that `old_root` is the hash of the first
+    /// [`old_len`](ConsistencyProof::old_len) bytes of the content behind
+    /// `new_root`, and that the content behind `new_root` is
+    /// [`new_len`](ConsistencyProof::new_len) bytes long. Both hashes must
+    /// have been computed with `mode`.
+    pub fn verify(
+        &self,
+        mode: &Mode,
+        old_root: &Hash,
+        new_root: &Hash,
+    ) -> Result<(), ConsistencyError> {
+        let layout = Layout::new(self.old_len, self.new_len);
+        let mut hasher = Hasher::new_internal(&mode.key, mode.flags);
+        let mut cvs = self.cvs.iter();
+        for (&subtree, cv) in layout.left.iter().zip(&mut cvs) {
+            push_subtree(&mut hasher, subtree, cv);
+        }
+        let old_split_len = match layout.split_chunk {
+            Some((index, _)) => {
+                hasher.chunk_state.chunk_counter = index;
+                (self.old_len - index * CHUNK_LEN as u64) as usize
+            }
+            None => 0,
+        };
+        hasher
+            .chunk_state
+            .update(&self.split_chunk[..old_split_len]);
+        if hasher.finalize() != *old_root {
+            return Err(ConsistencyError(ConsistencyErrorInner::OldRootMismatch));
+        }
+
+        // Now continue hashing from there, as if the old content had been
+        // followed by the new content all along.
+        if let Some((index, _)) = layout.split_chunk {
+            hasher
+                .chunk_state
+                .update(&self.split_chunk[old_split_len..]);
+            if !layout.right.is_empty() {
+                let chunk_cv = hasher.chunk_state.output().chaining_value();
+                push_subtree(&mut hasher, (index, 1), &chunk_cv);
+            }
+        }
+        for (&subtree, cv) in layout.right.iter().zip(&mut cvs) {
+            push_subtree(&mut hasher, subtree, cv);
+        }
+        if hasher.finalize() != *new_root {
+            return Err(ConsistencyError(ConsistencyErrorInner::NewRootMismatch));
+        }
+        Ok(())
+    }
+
+    /// Serialize the proof. The format is the old and new lengths as
+    /// little-endian 8-byte integers, followed by the bytes of the split
+    /// chunk, if any, followed by the chaining values. The number of bytes in
+    /// the split chunk and the number of chaining values are determined by
+    /// the two lengths.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(16 + self.split_chunk.len() + self.cvs.len() * OUT_LEN);
+        bytes.extend_from_slice(&self.old_len.to_le_bytes());
+        bytes.extend_from_slice(&self.new_len.to_le_bytes());
+        bytes.extend_from_slice(&self.split_chunk);
+        for cv in &self.cvs {
+            bytes.extend_from_slice(cv);
+        }
+        bytes
+    }
+
+    /// Deserialize a proof written by
+    /// [`to_bytes`](ConsistencyProof::to_bytes). This returns an error if the
+    /// lengths are invalid or if the proof is the wrong size for them. It
+    /// doesn't check anything else; that's what
+    /// [`verify`](ConsistencyProof::verify) is for.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ConsistencyError> {
+        use ConsistencyErrorInner::*;
+        if bytes.len() < 16 {
+            return Err(ConsistencyError(InvalidLen(bytes.len())));
+        }
+        let old_len = u64::from_le_bytes(*array_ref!(bytes, 0, 8));
+        let new_len = u64::from_le_bytes(*array_ref!(bytes, 8, 8));
+        if old_len > new_len {
+            return Err(ConsistencyError(OldLenTooLong { old_len, new_len }));
+        }
+        let layout = Layout::new(old_len, new_len);
+        let split_chunk_len = layout.split_chunk_len();
+        if bytes.len() != 16 + split_chunk_len + layout.num_cvs() * OUT_LEN {
+            return Err(ConsistencyError(InvalidLen(bytes.len())));
+        }
+        let split_chunk = bytes[16..][..split_chunk_len].to_vec();
+        let cvs = bytes[16 + split_chunk_len..]
+            .chunks_exact(OUT_LEN)
+            .map(|cv| *array_ref!(cv, 0, OUT_LEN))
+            .collect();
+        Ok(Self {
+            old_len,
+            new_len,
+            split_chunk,
+            cvs,
+        })
+    }
+}
+
+// Push the CV of a complete subtree onto the hasher's CV stack, and move its
+// chunk counter past the subtree, the same way Hasher::update does.
+fn push_subtree(hasher: &mut Hasher, (start, size): Subtree, cv: &CVBytes) {
+    hasher.push_cv(cv, start);
+    hasher.chunk_state = ChunkState::new(
+        &hasher.key,
+        start + size,
+        hasher.chunk_state.flags,
+        hasher.chunk_state.platform,
+    );
+}
+
+/// The error type for [`ConsistencyProof`].
+///
+/// The `.to_string()` representation of this error currently describes what
+/// failed. This is to help with logging and debugging, but it isn't a stable
+/// API detail, and it may change at any time.
+#[derive(Clone, Debug)]
+pub struct ConsistencyError(ConsistencyErrorInner);
+
+#[derive(Clone, Debug)]
+enum ConsistencyErrorInner {
+    InvalidLen(usize),
+    OldLenTooLong { old_len: u64, new_len: u64 },
+    OldRootMismatch,
+    NewRootMismatch,
+}
+
+impl fmt::Display for ConsistencyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            ConsistencyErrorInner::InvalidLen(len) => {
+                write!(f, "invalid consistency proof length: {}", len)
+            }
+            ConsistencyErrorInner::OldLenTooLong { old_len, new_len } => write!(
+                f,
+                "old length {} is greater than new length {}",
+                old_len, new_len
+            ),
+            ConsistencyErrorInner::OldRootMismatch => write!(f, "old root hash doesn't match"),
+            ConsistencyErrorInner::NewRootMismatch => write!(f, "new root hash doesn't match"),
+        }
+    }
+}
+
+impl std::error::Error for ConsistencyError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{paint_test_input, TEST_KEY};
+
+    fn check(mode: &Mode, new_tree: &HashTree, content: &[u8], hash: impl Fn(&[u8]) -> Hash) {
+        let new_root = new_tree.finalize();
+        for old_len in 0..=content.len() {
+            let proof = prove(new_tree, content, old_len as u64);
+            let old_root = hash(&content[..old_len]);
+            proof.verify(mode, &old_root, &new_root).unwrap();
+
+            // Round trip through bytes.
+            let proof = ConsistencyProof::from_bytes(&proof.to_bytes()).unwrap();
+            proof.verify(mode, &old_root, &new_root).unwrap();
+            assert!(proof.cvs.len() <= MAX_CVS);
+
+            // Swapping the roots or changing the old root fails.
+            if old_len < content.len() {
+                proof.verify(mode, &new_root, &old_root).unwrap_err();
+            }
+            let wrong_old_root = hash(&content[..old_len / 2]);
+            if old_len > 0 {
+                proof.verify(mode, &wrong_old_root, &new_root).unwrap_err();
+            }
+        }
+    }
+
+    #[test]
+    fn test_prove_and_verify() {
+        const CONTEXT: &str = "consistency proof test context";
+        // Step through lengths one byte at a time around chunk boundaries, and
+        // in bigger steps elsewhere, to keep this test fast.
+        let mut content = vec![0; 9 * CHUNK_LEN + 1];
+        paint_test_input(&mut content);
+        for &new_len in &[0, 1, CHUNK_LEN, CHUNK_LEN + 1, 4 * CHUNK_LEN, content.len()] {
+            dbg!(new_len);
+            let content = &content[..new_len];
+            check(&Mode::hash(), &HashTree::new(content), content, crate::hash);
+            check(
+                &Mode::keyed_hash(&TEST_KEY),
+                &HashTree::with_mode(&Mode::keyed_hash(&TEST_KEY), content),
+                content,
+                |input| crate::keyed_hash(&TEST_KEY, input),
+            );
+            check(
+                &Mode::derive_key(CONTEXT),
+                &HashTree::with_mode(&Mode::derive_key(CONTEXT), content),
+                content,
+                |input| Hash(crate::derive_key(CONTEXT, input)),
+            );
+        }
+    }
+
+    #[test]
+    fn test_max_cvs() {
+        // The longest content, with old lengths whose chunk counts have many
+        // 1 bits and many carries on the right side.
+        let max_chunks = chunk_count(u64::MAX);
+        let mut worst = 0;
+        for &old_chunks in &[
+            1,
+            2,
+            3,
+            max_chunks / 3,
+            max_chunks / 3 + 1,
+            2 * (max_chunks / 3),
+            max_chunks / 2 + 1,
+            max_chunks - 1,
+            max_chunks,
+        ] {
+            let end = old_chunks.saturating_mul(CHUNK_LEN as u64);
+            for &old_len in &[end, end - 1] {
+                for &new_len in &[u64::MAX, u64::MAX - CHUNK_LEN as u64] {
+                    if old_len <= new_len {
+                        let num_cvs = Layout::new(old_len, new_len).num_cvs();
+                        assert!(num_cvs <= MAX_CVS, "{} {}", old_len, new_len);
+                        worst = cmp::max(worst, num_cvs);
+                    }
+                }
+            }
+        }
+        // The bound is close to tight.
+        assert!(worst >= MAX_CVS - 2, "{}", worst);
+    }
+
+    #[test]
+    fn test_tampering() {
+        let mut content = vec![0; 10 * CHUNK_LEN];
+        paint_test_input(&mut content);
+        let tree = HashTree::new(&content);
+        let old_len = 3 * CHUNK_LEN + 100;
+        let old_root = crate::hash(&content[..old_len]);
+        let new_root = tree.finalize();
+        let bytes = prove(&tree, &content, old_len as u64).to_bytes();
+
+        // Flipping any bit of the proof makes it fail, one way or another.
+        for i in 0..bytes.len() {
+            let mut corrupt = bytes.clone();
+            corrupt[i] ^= 1;
+            if let Ok(proof) = ConsistencyProof::from_bytes(&corrupt) {
+                proof
+                    .verify(&Mode::hash(), &old_root, &new_root)
+                    .unwrap_err();
+            }
+        }
+
+        // Wrong sizes and lengths are errors.
+        ConsistencyProof::from_bytes(&bytes[..bytes.len() - 1]).unwrap_err();
+        let mut swapped = bytes.clone();
+        swapped[..8].copy_from_slice(&(11 * CHUNK_LEN as u64).to_le_bytes());
+        let err = ConsistencyProof::from_bytes(&swapped).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "old length 11264 is greater than new length 10240"
+        );
+
+        // A different mode fails.
+        let proof = ConsistencyProof::from_bytes(&bytes).unwrap();
+        proof
+            .verify(&Mode::keyed_hash(&TEST_KEY), &old_root, &new_root)
+            .unwrap_err();
+    }
+}
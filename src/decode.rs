@@ -0,0 +1,725 @@
+//! Decode and verify encodings produced by the [`encode`](crate::encode)
+//! module.
+//!
+//! A [`Decoder`] reads a combined encoding, or content along with an outboard
+//! encoding, and checks everything it reads against a trusted root [`Hash`].
+//! Each chunk is verified before any of its bytes are returned, so corruption
+//! is caught at the first bad chunk, rather than after reading everything.
+//!
+//! Verification failures are reported as [`io::Error`]s of kind
+//! [`InvalidData`](io::ErrorKind::InvalidData), which wrap a [`DecodeError`]
+//! that says which chunk failed:
+//!
+//! ```
+//! # use std::io::prelude::*;
+//! let input = vec![0xab; 10_000];
+//! let (mut encoded, hash) = blake3::encode::encode(&input);
+//! // Corrupt the last byte, which is in the last chunk.
+//! *encoded.last_mut().unwrap() ^= 1;
+//!
+//! let mut decoder = blake3::decode::Decoder::new(&encoded[..], &hash);
+//! let mut output = Vec::new();
+//! let err = decoder.read_to_end(&mut output).unwrap_err();
+//! let decode_err = err
+//!     .get_ref()
+//!     .and_then(|e| e.downcast_ref::<blake3::decode::DecodeError>())
+//!     .unwrap();
+//! assert_eq!(decode_err.chunk_index(), 9);
+//! // All of the preceding chunks were verified and returned.
+//! assert_eq!(output, &input[..9 * 1024]);
+//! ```
+//!
+//! A [`SliceDecoder`] verifies a slice produced by
+//! [`encode::extract_slice`](crate::encode::extract_slice), and returns only
+//! the bytes of the requested range:
+//!
+//! ```
+//! # use std::io::prelude::*;
+//! let input = vec![0xab; 100_000];
+//! let (encoded, hash) = blake3::encode::encode(&input);
+//! let mut slice = Vec::new();
+//! blake3::encode::extract_slice(std::io::Cursor::new(&encoded), 50_000, 2_000, &mut slice)?;
+//! assert!(slice.len() < encoded.len() / 10);
+//!
+//! let content = blake3::decode::decode_slice(&slice, &hash, 50_000, 2_000)?;
+//! assert_eq!(content, &input[50_000..52_000]);
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! This module is gated by the `std` Cargo feature, which is enabled by
+//! default.
+
+use crate::encode::{
+    left_len, slice_chunks, subtree_encoded_size, subtree_in_slice, HEADER_SIZE, PARENT_SIZE,
+};
+use crate::platform::Platform;
+use crate::{parent_node_output, CVBytes, ChunkState, Hash, CHUNK_LEN, IV, MAX_DEPTH, OUT_LEN};
+use arrayref::array_ref;
+use arrayvec::ArrayVec;
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+/// Decode a combined encoding all at once, and return the content. See
+/// [`encode::encode`](crate::encode::encode).
+pub fn decode(encoded: impl AsRef<[u8]>, hash: &Hash) -> io::Result<Vec<u8>> {
+    let mut decoder = Decoder::new(encoded.as_ref(), hash);
+    let mut content = Vec::new();
+    decoder.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/// Verify a slice all at once, and return the content it covers. See
+/// [`SliceDecoder`].
+pub fn decode_slice(
+    slice: impl AsRef<[u8]>,
+    hash: &Hash,
+    slice_start: u64,
+    slice_len: u64,
+) -> io::Result<Vec<u8>> {
+    let mut decoder = SliceDecoder::new(slice.as_ref(), hash, slice_start, slice_len);
+    let mut content = Vec::new();
+    decoder.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/// The error for a chunk or parent node that doesn't match the tree above it.
+///
+/// This is wrapped in an [`io::Error`] of kind
+/// [`InvalidData`](io::ErrorKind::InvalidData).
+#[derive(Clone, Debug)]
+pub struct DecodeError {
+    chunk_index: u64,
+}
+
+impl DecodeError {
+    /// The index of the chunk that failed verification. If a parent node above
+    /// the chunk is the one that didn't match, this is still the index of the
+    /// chunk that the decoder was trying to reach.
+    pub fn chunk_index(&self) -> u64 {
+        self.chunk_index
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "hash mismatch at chunk {}", self.chunk_index)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<DecodeError> for io::Error {
+    fn from(e: DecodeError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+// A subtree that hasn't been read yet, along with the chaining value that it
+// needs to match.
+#[derive(Clone, Copy, Debug)]
+struct Subtree {
+    cv: CVBytes,
+    start: u64,
+    len: u64,
+    is_root: bool,
+}
+
+impl Subtree {
+    fn chunk_index(&self) -> u64 {
+        self.start / CHUNK_LEN as u64
+    }
+
+    fn check(&self, output: crate::Output) -> Result<(), DecodeError> {
+        let cv = if self.is_root {
+            *output.root_hash().as_bytes()
+        } else {
+            output.chaining_value()
+        };
+        if constant_time_eq::constant_time_eq_32(&cv, &self.cv) {
+            Ok(())
+        } else {
+            Err(DecodeError {
+                chunk_index: self.chunk_index(),
+            })
+        }
+    }
+}
+
+/// An incremental decoder, which verifies an encoding as it reads it.
+///
+/// `Decoder` implements [`Read`], and if the underlying readers implement
+/// [`Seek`], it implements `Seek` too. Reading from the start of the content
+/// needs no seeking in the underlying readers. Seeking verifies only the path
+/// of parent nodes from the root down to the target chunk, and then the
+/// target chunk itself. Seeking to or past the end of the content verifies
+/// the final chunk, which is what authenticates the length header.
+///
+/// The encoding (or the outboard encoding) must start at offset 0 of its
+/// underlying reader for seeking to work. If an error occurs, the decoder's
+/// position is unspecified, and further reads may fail. Seeking rebuilds the
+/// decoder's state from the root.
+pub struct Decoder<T: Read, O: Read> {
+    input: T,
+    outboard: Option<O>,
+    hash: Hash,
+    platform: Platform,
+    content_len: Option<u64>,
+    // The subtrees that haven't been read yet, in reverse pre-order, so the
+    // next one to read is on top.
+    stack: ArrayVec<Subtree, { MAX_DEPTH + 1 }>,
+    // The current position in the content.
+    position: u64,
+    // The most recently verified chunk.
+    buf: [u8; CHUNK_LEN],
+    buf_start: u64,
+    buf_len: usize,
+    final_chunk_verified: bool,
+    // The start and length of the content range, when reading a slice.
+    slice: Option<(u64, u64)>,
+}
+
+impl<T: Read> Decoder<T, T> {
+    /// Construct a new `Decoder` for a combined encoding.
+    pub fn new(encoded: T, hash: &Hash) -> Self {
+        Self::new_internal(encoded, None, hash)
+    }
+}
+
+impl<T: Read, O: Read> Decoder<T, O> {
+    fn new_internal(input: T, outboard: Option<O>, hash: &Hash) -> Self {
+        Self {
+            input,
+            outboard,
+            hash: *hash,
+            platform: Platform::detect(),
+            content_len: None,
+            stack: ArrayVec::new(),
+            position: 0,
+            buf: [0; CHUNK_LEN],
+            buf_start: 0,
+            buf_len: 0,
+            final_chunk_verified: false,
+            slice: None,
+        }
+    }
+
+    /// Construct a new `Decoder` for content stored separately from its
+    /// outboard encoding.
+    pub fn new_outboard(content: T, outboard: O, hash: &Hash) -> Self {
+        Self::new_internal(content, Some(outboard), hash)
+    }
+
+    // Parent nodes and the header come from the outboard encoding if there is
+    // one.
+    fn tree_reader(&mut self) -> &mut dyn Read {
+        match &mut self.outboard {
+            Some(outboard) => outboard,
+            None => &mut self.input,
+        }
+    }
+
+    fn root(&self, content_len: u64) -> Subtree {
+        Subtree {
+            cv: *self.hash.as_bytes(),
+            start: 0,
+            len: content_len,
+            is_root: true,
+        }
+    }
+
+    // Read the length header from the current position of the tree reader,
+    // if we haven't already.
+    fn read_header(&mut self) -> io::Result<u64> {
+        if let Some(len) = self.content_len {
+            return Ok(len);
+        }
+        let mut header = [0; HEADER_SIZE];
+        self.tree_reader().read_exact(&mut header)?;
+        let len = u64::from_le_bytes(header);
+        self.content_len = Some(len);
+        Ok(len)
+    }
+
+    // Read and verify a parent node, and return its children.
+    fn read_parent(&mut self, subtree: &Subtree, chunk_index: u64) -> io::Result<[Subtree; 2]> {
+        debug_assert!(subtree.len > CHUNK_LEN as u64);
+        let mut parent = [0; PARENT_SIZE];
+        self.tree_reader().read_exact(&mut parent)?;
+        let left_cv = array_ref!(parent, 0, OUT_LEN);
+        let right_cv = array_ref!(parent, OUT_LEN, OUT_LEN);
+        let output = parent_node_output(left_cv, right_cv, IV, 0, self.platform);
+        subtree
+            .check(output)
+            .map_err(|_| DecodeError { chunk_index })?;
+        let left_len = left_len(subtree.len);
+        Ok([
+            Subtree {
+                cv: *left_cv,
+                start: subtree.start,
+                len: left_len,
+                is_root: false,
+            },
+            Subtree {
+                cv: *right_cv,
+                start: subtree.start + left_len,
+                len: subtree.len - left_len,
+                is_root: false,
+            },
+        ])
+    }
+
+    // Read and verify a chunk into the buffer.
+    fn read_chunk(&mut self, subtree: &Subtree) -> io::Result<()> {
+        debug_assert!(subtree.len <= CHUNK_LEN as u64);
+        // Invalidate the buffer first, in case of errors.
+        self.buf_len = 0;
+        let len = subtree.len as usize;
+        self.input.read_exact(&mut self.buf[..len])?;
+        let mut chunk_state = ChunkState::new(IV, subtree.chunk_index(), 0, self.platform);
+        chunk_state.update(&self.buf[..len]);
+        subtree.check(chunk_state.output())?;
+        self.buf_start = subtree.start;
+        self.buf_len = len;
+        if subtree.start + subtree.len == self.content_len.unwrap() {
+            self.final_chunk_verified = true;
+        }
+        Ok(())
+    }
+
+    // Read the next chunk in order, verifying any parent nodes in the way.
+    fn read_next_chunk(&mut self) -> io::Result<()> {
+        if self.content_len.is_none() {
+            let len = self.read_header()?;
+            let root = self.root(len);
+            self.stack.push(root);
+            if let Some((slice_start, _)) = self.slice {
+                self.position = slice_start;
+            }
+        }
+        while let Some(subtree) = self.stack.pop() {
+            if subtree.len <= CHUNK_LEN as u64 {
+                return self.read_chunk(&subtree);
+            }
+            let [left, right] = self.read_parent(&subtree, subtree.chunk_index())?;
+            // A slice only includes the subtrees that overlap its range.
+            let slice = self
+                .slice
+                .map(|(start, len)| slice_chunks(start, len, self.content_len.unwrap()));
+            for child in [right, left] {
+                let in_slice = match slice {
+                    Some(slice) => subtree_in_slice(child.start, child.len, slice),
+                    None => true,
+                };
+                if in_slice {
+                    self.stack.push(child);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn at_verified_eof(&self) -> bool {
+        match self.content_len {
+            Some(len) => self.position >= len && self.final_chunk_verified,
+            None => false,
+        }
+    }
+
+    fn buf_contains(&self, position: u64) -> bool {
+        self.buf_start <= position && position < self.buf_start + self.buf_len as u64
+    }
+}
+
+impl<T: Read, O: Read> Read for Decoder<T, O> {
+    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        if !self.buf_contains(self.position) {
+            if !self.at_verified_eof() {
+                self.read_next_chunk()?;
+            }
+            if !self.buf_contains(self.position) {
+                if self.at_verified_eof() {
+                    return Ok(0);
+                }
+                // This only happens if an earlier read failed partway.
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "decoder can't continue after an error without seeking",
+                ));
+            }
+        }
+        let buf_offset = (self.position - self.buf_start) as usize;
+        let available = &self.buf[buf_offset..self.buf_len];
+        let take = cmp::min(available.len(), output.len());
+        output[..take].copy_from_slice(&available[..take]);
+        self.position += take as u64;
+        Ok(take)
+    }
+}
+
+impl<T: Read + Seek, O: Read + Seek> Decoder<T, O> {
+    // Walk down from the root to the chunk containing target, verifying each
+    // parent node along the way, and then read and verify that chunk. The
+    // right siblings of the path go on the stack, so that reading can
+    // continue in order without seeking.
+    fn seek_to_chunk(&mut self, target: u64) -> io::Result<()> {
+        let content_len = self.content_len.unwrap();
+        let outboard = self.outboard.is_some();
+        let chunk_index = target / CHUNK_LEN as u64;
+        self.stack.clear();
+        self.buf_len = 0;
+        let mut subtree = self.root(content_len);
+        let mut encoded_offset = HEADER_SIZE as u128;
+        while subtree.len > CHUNK_LEN as u64 {
+            self.seek_tree_reader(encoded_offset)?;
+            let [left, right] = self.read_parent(&subtree, chunk_index)?;
+            encoded_offset += PARENT_SIZE as u128;
+            if target < right.start {
+                self.stack.push(right);
+                subtree = left;
+            } else {
+                encoded_offset += subtree_encoded_size(left.len, outboard);
+                subtree = right;
+            }
+        }
+        if outboard {
+            self.input.seek(SeekFrom::Start(subtree.start))?;
+        } else {
+            self.seek_tree_reader(encoded_offset)?;
+        }
+        self.read_chunk(&subtree)
+    }
+
+    fn seek_tree_reader(&mut self, offset: u128) -> io::Result<()> {
+        let offset = u64::try_from(offset)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "encoding too long"))?;
+        match &mut self.outboard {
+            Some(outboard) => outboard.seek(SeekFrom::Start(offset))?,
+            None => self.input.seek(SeekFrom::Start(offset))?,
+        };
+        Ok(())
+    }
+}
+
+impl<T: Read + Seek, O: Read + Seek> Seek for Decoder<T, O> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let content_len = match self.content_len {
+            Some(len) => len,
+            None => {
+                self.seek_tree_reader(0)?;
+                self.read_header()?
+            }
+        };
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => add_offset(self.position, n),
+            SeekFrom::End(n) => add_offset(content_len, n),
+        };
+        let target = target
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek position"))?;
+        if target >= content_len {
+            // Seeking to the end verifies the final chunk, which
+            // authenticates the length.
+            if !self.final_chunk_verified {
+                let last_chunk_start =
+                    content_len.saturating_sub(1) / CHUNK_LEN as u64 * CHUNK_LEN as u64;
+                self.seek_to_chunk(last_chunk_start)?;
+            }
+        } else if !self.buf_contains(target) {
+            self.seek_to_chunk(target)?;
+        }
+        self.position = target;
+        Ok(target)
+    }
+}
+
+fn add_offset(position: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        position.checked_add(offset as u64)
+    } else {
+        position.checked_sub(offset.unsigned_abs())
+    }
+}
+
+impl<T: Read, O: Read> fmt::Debug for Decoder<T, O> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Decoder")
+            .field("outboard", &self.outboard.is_some())
+            .field("hash", &self.hash)
+            .field("content_len", &self.content_len)
+            .field("position", &self.position)
+            .finish()
+    }
+}
+
+/// An incremental decoder for a slice, which verifies the slice as it reads
+/// it and returns only the content in the slice's range.
+///
+/// `slice_start` and `slice_len` must be the same as were given to
+/// [`extract_slice`](crate::encode::extract_slice). The first chunk of the
+/// slice is always verified, even if the range is empty, so a slice that
+/// starts at or past the end of the content proves the content length. Reads
+/// stop at the end of the range or the end of the content, whichever comes
+/// first.
+///
+/// Like the full decoder, the length header is only authenticated by the
+/// final chunk. A slice that doesn't include the final chunk still proves
+/// that its bytes are in the content at the right offsets, but it doesn't
+/// prove the total length of the content.
+pub struct SliceDecoder<T: Read> {
+    inner: Decoder<T, T>,
+    slice_start: u64,
+    slice_len: u64,
+    first_chunk_verified: bool,
+}
+
+impl<T: Read> SliceDecoder<T> {
+    /// Construct a new `SliceDecoder`.
+    pub fn new(slice: T, hash: &Hash, slice_start: u64, slice_len: u64) -> Self {
+        let mut inner = Decoder::new(slice, hash);
+        inner.slice = Some((slice_start, slice_len));
+        Self {
+            inner,
+            slice_start,
+            slice_len,
+            first_chunk_verified: false,
+        }
+    }
+
+    /// Return the underlying reader.
+    pub fn into_inner(self) -> T {
+        self.inner.input
+    }
+}
+
+impl<T: Read> Read for SliceDecoder<T> {
+    fn read(&mut self, output: &mut [u8]) -> io::Result<usize> {
+        if !self.first_chunk_verified {
+            self.inner.read_next_chunk()?;
+            self.first_chunk_verified = true;
+        }
+        let content_len = self.inner.content_len.unwrap();
+        let slice_end = cmp::min(self.slice_start.saturating_add(self.slice_len), content_len);
+        let remaining = slice_end.saturating_sub(self.inner.position);
+        if remaining == 0 {
+            return Ok(0);
+        }
+        let take = cmp::min(remaining, output.len() as u64) as usize;
+        self.inner.read(&mut output[..take])
+    }
+}
+
+impl<T: Read> fmt::Debug for SliceDecoder<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SliceDecoder")
+            .field("hash", &self.inner.hash)
+            .field("slice_start", &self.slice_start)
+            .field("slice_len", &self.slice_len)
+            .field("content_len", &self.inner.content_len)
+            .field("position", &self.inner.position)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encode;
+    use crate::test::{paint_test_input, TEST_CASES};
+    use std::io::Cursor;
+
+    fn decode_err_chunk(err: &io::Error) -> u64 {
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        err.get_ref()
+            .unwrap()
+            .downcast_ref::<DecodeError>()
+            .unwrap()
+            .chunk_index()
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut input = vec![0; crate::test::TEST_CASES_MAX];
+        paint_test_input(&mut input);
+        for &case in TEST_CASES {
+            let input = &input[..case];
+            let (encoded, hash) = encode::encode(input);
+            assert_eq!(decode(&encoded, &hash).unwrap(), input);
+
+            let (outboard, outboard_hash) = encode::outboard(input);
+            assert_eq!(hash, outboard_hash);
+            let mut decoder = Decoder::new_outboard(input, &outboard[..], &hash);
+            let mut output = Vec::new();
+            decoder.read_to_end(&mut output).unwrap();
+            assert_eq!(output, input);
+
+            // The wrong hash fails at chunk 0.
+            let wrong_hash = crate::hash(b"wrong");
+            let err = decode(&encoded, &wrong_hash).unwrap_err();
+            assert_eq!(decode_err_chunk(&err), 0);
+        }
+    }
+
+    #[test]
+    fn test_corruption() {
+        let mut input = vec![0; 5 * CHUNK_LEN + 1];
+        paint_test_input(&mut input);
+        let (encoded, hash) = encode::encode(&input);
+        // The tree for 6 chunks is: root(P(P(c0, c1), P(c2, c3)), P(c4, c5)).
+        // Flip a byte in each chunk and each parent node, and check which
+        // chunk the error names, and that everything before it was returned.
+        let cases = [
+            // (tree offset to flip, chunk index of the error, bytes returned)
+            (0, 0, 0),                          // root
+            (64, 0, 0),                         // P(0-3)
+            (128, 0, 0),                        // P(c0, c1)
+            (192, 0, 0),                        // c0
+            (192 + 1024, 1, 1024),              // c1
+            (192 + 2048, 2, 2048),              // P(c2, c3)
+            (192 + 2048 + 64, 2, 2048),         // c2
+            (192 + 2048 + 64 + 1024, 3, 3072),  // c3
+            (192 + 2048 + 64 + 2048, 4, 4096),  // P(c4, c5)
+            (192 + 4096 + 128, 4, 4096),        // c4
+            (192 + 4096 + 128 + 1024, 5, 5120), // c5
+        ];
+        for &(offset, expected_index, expected_output) in &cases {
+            let mut bad = encoded.clone();
+            bad[HEADER_SIZE + offset] ^= 1;
+            let mut decoder = Decoder::new(&bad[..], &hash);
+            let mut output = Vec::new();
+            let err = decoder.read_to_end(&mut output).unwrap_err();
+            assert_eq!(decode_err_chunk(&err), expected_index);
+            assert_eq!(output, &input[..expected_output]);
+        }
+
+        // A truncated encoding is an EOF error.
+        let err = decode(&encoded[..encoded.len() - 1], &hash).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // A length header that disagrees with the tree fails too, even when
+        // the number of chunks is the same.
+        let mut bad = encoded.clone();
+        bad[0] += 1;
+        bad.push(0);
+        let err = decode(&bad, &hash).unwrap_err();
+        assert_eq!(decode_err_chunk(&err), 5);
+    }
+
+    #[test]
+    fn test_seek() {
+        let mut input = vec![0; 9 * CHUNK_LEN + 100];
+        paint_test_input(&mut input);
+        let (encoded, hash) = encode::encode(&input);
+        let (outboard, _) = encode::outboard(&input);
+        let mut combined = Decoder::new(Cursor::new(&encoded), &hash);
+        let mut separate =
+            Decoder::new_outboard(Cursor::new(&input), Cursor::new(&outboard), &hash);
+        let seeks = [
+            SeekFrom::Start(5000),
+            SeekFrom::Start(0),
+            SeekFrom::End(-1),
+            SeekFrom::Current(-2000),
+            SeekFrom::Start(1023),
+            SeekFrom::Current(1),
+            SeekFrom::Start(input.len() as u64),
+            SeekFrom::Start(input.len() as u64 + 42),
+            SeekFrom::Start(9 * CHUNK_LEN as u64),
+        ];
+        for decoder in [
+            &mut combined as &mut dyn ReadSeek,
+            &mut separate as &mut dyn ReadSeek,
+        ] {
+            for &seek in &seeks {
+                let position = decoder.seek(seek).unwrap() as usize;
+                let mut output = Vec::new();
+                (&mut *decoder).take(1500).read_to_end(&mut output).unwrap();
+                let start = cmp::min(position, input.len());
+                let end = cmp::min(position + 1500, input.len());
+                assert_eq!(output, &input[start..end], "{:?}", seek);
+            }
+            assert!(decoder.seek(SeekFrom::Current(-1_000_000)).is_err());
+        }
+
+        // Seeking only touches the path to the target chunk. Corrupting chunk
+        // 3 doesn't prevent seeking to chunk 4 and reading from there.
+        let mut bad = encoded.clone();
+        let chunk_3_offset = HEADER_SIZE + 5 * PARENT_SIZE + 3 * CHUNK_LEN;
+        bad[chunk_3_offset] ^= 1;
+        let mut decoder = Decoder::new(Cursor::new(&bad), &hash);
+        decoder.seek(SeekFrom::Start(4 * CHUNK_LEN as u64)).unwrap();
+        let mut output = Vec::new();
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(output, &input[4 * CHUNK_LEN..]);
+        let err = decoder
+            .seek(SeekFrom::Start(3 * CHUNK_LEN as u64))
+            .unwrap_err();
+        assert_eq!(decode_err_chunk(&err), 3);
+    }
+
+    trait ReadSeek: Read + Seek {}
+    impl<T: Read + Seek> ReadSeek for T {}
+
+    #[test]
+    fn test_slices() {
+        let mut input = vec![0; 10 * CHUNK_LEN + 1];
+        paint_test_input(&mut input);
+        let cases: &[(usize, u64, u64)] = &[
+            // (input length, slice start, slice length)
+            (0, 0, 0),
+            (0, 0, 100),
+            (100, 10, 20),
+            (100, 100, 10),
+            (CHUNK_LEN, 0, CHUNK_LEN as u64),
+            (4 * CHUNK_LEN, CHUNK_LEN as u64, 1),
+            (4 * CHUNK_LEN, 2 * CHUNK_LEN as u64 - 1, 2),
+            (10 * CHUNK_LEN + 1, 0, 0),
+            (10 * CHUNK_LEN + 1, 3000, 4000),
+            (10 * CHUNK_LEN + 1, 5000, u64::MAX),
+            (10 * CHUNK_LEN + 1, 10 * CHUNK_LEN as u64, 1),
+            (10 * CHUNK_LEN + 1, 20 * CHUNK_LEN as u64, 1),
+            (10 * CHUNK_LEN + 1, u64::MAX, u64::MAX),
+        ];
+        for &(input_len, start, len) in cases {
+            let input = &input[..input_len];
+            let (encoded, hash) = encode::encode(input);
+            let (outboard, _) = encode::outboard(input);
+            let mut slice = Vec::new();
+            encode::extract_slice(Cursor::new(&encoded), start, len, &mut slice).unwrap();
+            let mut outboard_slice = Vec::new();
+            encode::extract_slice_outboard(
+                Cursor::new(input),
+                Cursor::new(&outboard),
+                start,
+                len,
+                &mut outboard_slice,
+            )
+            .unwrap();
+            assert_eq!(slice, outboard_slice);
+
+            let expected_start = cmp::min(start, input_len as u64) as usize;
+            let expected_end = cmp::min(start.saturating_add(len), input_len as u64) as usize;
+            let expected = &input[expected_start..cmp::max(expected_start, expected_end)];
+            assert_eq!(decode_slice(&slice, &hash, start, len).unwrap(), expected);
+
+            // Slices of at most a few chunks are smaller than the encoding.
+            if input_len > 4 * CHUNK_LEN && len <= CHUNK_LEN as u64 {
+                assert!(slice.len() < encoded.len() / 2);
+            }
+
+            // Any corruption in the tree is caught, and the wrong range
+            // doesn't verify. The length header is only fully authenticated
+            // by the final chunk.
+            for i in HEADER_SIZE..slice.len() {
+                let mut bad = slice.clone();
+                bad[i] ^= 1;
+                decode_slice(&bad, &hash, start, len).unwrap_err();
+            }
+            if input_len > CHUNK_LEN && start < CHUNK_LEN as u64 {
+                let far = input_len as u64 - 1;
+                decode_slice(&slice, &hash, far, 1).unwrap_err();
+            }
+        }
+    }
+}
@@ -0,0 +1,275 @@
+//! `DeriveKeyContext`, which hashes a key derivation context once so that it
+//! can be reused for any number of derivations.
+//!
+//! The first stage of key derivation hashes the context string into a context
+//! key. Everything here is a `const fn`, so that this stage can run in a
+//! `const` or `static` initializer. That rules out the SIMD implementations,
+//! and it also rules out `&mut` parameters on our MSRV, so the compression
+//! function below passes its state by value. Context strings are short, and
+//! this only runs once per context, so the speed of this code doesn't matter
+//! much.
+
+use crate::{
+    batch, hash_all_at_once, join, CVWords, Hasher, OutputReader, CHUNK_END, CHUNK_START,
+    DERIVE_KEY_CONTEXT, DERIVE_KEY_MATERIAL, IV, MAX_DEPTH, MSG_SCHEDULE, OUT_LEN, PARENT, ROOT,
+};
+use crate::{BLOCK_LEN, CHUNK_LEN};
+
+/// A key derivation context that has been hashed ahead of time.
+///
+/// [`derive_key`](crate::derive_key) and
+/// [`Hasher::new_derive_key`](crate::Hasher::new_derive_key) hash their context
+/// string on every call. When an application derives many keys under the same
+/// hardcoded context, a `DeriveKeyContext` does that work once, and each
+/// derivation only hashes the key material. The results are the same.
+///
+/// The constructors are `const`, so the context key can be computed at compile
+/// time.
+///
+/// # Example
+///
+/// ```
+/// static CONTEXT: blake3::DeriveKeyContext =
+///     blake3::DeriveKeyContext::new("example.com 2019-12-25 16:18:03 session tokens v1");
+///
+/// let key = CONTEXT.derive_key(b"user 42");
+/// assert_eq!(
+///     key,
+///     blake3::derive_key("example.com 2019-12-25 16:18:03 session tokens v1", b"user 42"),
+/// );
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeriveKeyContext {
+    context_key: CVWords,
+}
+
+impl DeriveKeyContext {
+    /// Hash a context string. See [`derive_key`](crate::derive_key) for the
+    /// requirements on context strings.
+    pub const fn new(context: &str) -> Self {
+        Self::new_raw(context.as_bytes())
+    }
+
+    /// As [`new`](DeriveKeyContext::new), but with a context string of raw
+    /// bytes. See [`derive_key_raw`](crate::derive_key_raw).
+    pub const fn new_raw(context: &[u8]) -> Self {
+        Self {
+            context_key: hash_context(context),
+        }
+    }
+
+    /// The same as [`derive_key`](crate::derive_key) with this context.
+    pub fn derive_key(&self, key_material: &[u8]) -> [u8; OUT_LEN] {
+        hash_all_at_once::<join::SerialJoin>(key_material, &self.context_key, DERIVE_KEY_MATERIAL)
+            .root_hash()
+            .0
+    }
+
+    /// The same as [`derive_key_xof`](crate::derive_key_xof) with this context.
+    pub fn derive_key_xof(&self, key_material: &[u8]) -> OutputReader {
+        OutputReader::new(hash_all_at_once::<join::SerialJoin>(
+            key_material,
+            &self.context_key,
+            DERIVE_KEY_MATERIAL,
+        ))
+    }
+
+    /// The same as [`Hasher::new_derive_key`](crate::Hasher::new_derive_key)
+    /// with this context.
+    pub fn hasher(&self) -> Hasher {
+        Hasher::new_internal(&self.context_key, DERIVE_KEY_MATERIAL)
+    }
+
+    /// The same as [`derive_key_many`](crate::derive_key_many) with this
+    /// context.
+    pub fn derive_key_many(&self, key_materials: &[&[u8]], out: &mut [[u8; OUT_LEN]]) {
+        batch::hash_many_serial(key_materials, out, &self.context_key, DERIVE_KEY_MATERIAL);
+    }
+
+    /// The same as [`derive_key_many_rayon`](crate::derive_key_many_rayon)
+    /// with this context.
+    #[cfg(feature = "rayon")]
+    pub fn derive_key_many_rayon(&self, key_materials: &[&[u8]], out: &mut [[u8; OUT_LEN]]) {
+        batch::hash_many_with_join::<join::RayonJoin, _>(
+            key_materials,
+            out,
+            &self.context_key,
+            DERIVE_KEY_MATERIAL,
+        );
+    }
+}
+
+const fn g(
+    mut state: [u32; 16],
+    a: usize,
+    b: usize,
+    c: usize,
+    d: usize,
+    x: u32,
+    y: u32,
+) -> [u32; 16] {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(x);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(y);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+    state
+}
+
+// This only returns the first 8 words of output, which is all that the first
+// stage of key derivation needs.
+const fn compress(
+    cv: &CVWords,
+    block: &[u32; 16],
+    block_len: usize,
+    counter: u64,
+    flags: u8,
+) -> CVWords {
+    let mut state = [
+        cv[0],
+        cv[1],
+        cv[2],
+        cv[3],
+        cv[4],
+        cv[5],
+        cv[6],
+        cv[7],
+        IV[0],
+        IV[1],
+        IV[2],
+        IV[3],
+        counter as u32,
+        (counter >> 32) as u32,
+        block_len as u32,
+        flags as u32,
+    ];
+    let mut round = 0;
+    while round < 7 {
+        let s = &MSG_SCHEDULE[round];
+        state = g(state, 0, 4, 8, 12, block[s[0]], block[s[1]]);
+        state = g(state, 1, 5, 9, 13, block[s[2]], block[s[3]]);
+        state = g(state, 2, 6, 10, 14, block[s[4]], block[s[5]]);
+        state = g(state, 3, 7, 11, 15, block[s[6]], block[s[7]]);
+        state = g(state, 0, 5, 10, 15, block[s[8]], block[s[9]]);
+        state = g(state, 1, 6, 11, 12, block[s[10]], block[s[11]]);
+        state = g(state, 2, 7, 8, 13, block[s[12]], block[s[13]]);
+        state = g(state, 3, 4, 9, 14, block[s[14]], block[s[15]]);
+        round += 1;
+    }
+    let mut out = [0; 8];
+    let mut i = 0;
+    while i < 8 {
+        out[i] = state[i] ^ state[i + 8];
+        i += 1;
+    }
+    out
+}
+
+// Load up to one block of input starting at `start` as little-endian words,
+// zero-padded.
+const fn block_words(input: &[u8], start: usize, len: usize) -> [u32; 16] {
+    let mut words = [0; 16];
+    let mut i = 0;
+    while i < len {
+        words[i / 4] |= (input[start + i] as u32) << (8 * (i % 4));
+        i += 1;
+    }
+    words
+}
+
+// The final compression of a chunk or a parent node, which gets the ROOT flag
+// if it's the root of the tree.
+struct Output {
+    cv: CVWords,
+    block: [u32; 16],
+    block_len: usize,
+    counter: u64,
+    flags: u8,
+}
+
+impl Output {
+    const fn chaining_value(&self) -> CVWords {
+        compress(
+            &self.cv,
+            &self.block,
+            self.block_len,
+            self.counter,
+            self.flags,
+        )
+    }
+
+    const fn root_hash(&self) -> CVWords {
+        // A root is always a single chunk or a parent node, so its counter is 0.
+        compress(&self.cv, &self.block, self.block_len, 0, self.flags | ROOT)
+    }
+}
+
+const fn chunk_output(input: &[u8], start: usize, len: usize, chunk_counter: u64) -> Output {
+    let mut cv = *IV;
+    let mut offset = 0;
+    let mut flags = DERIVE_KEY_CONTEXT | CHUNK_START;
+    // All blocks but the last get compressed here. An empty chunk still has
+    // one (empty) final block.
+    while len - offset > BLOCK_LEN {
+        let block = block_words(input, start + offset, BLOCK_LEN);
+        cv = compress(&cv, &block, BLOCK_LEN, chunk_counter, flags);
+        offset += BLOCK_LEN;
+        flags = DERIVE_KEY_CONTEXT;
+    }
+    Output {
+        cv,
+        block: block_words(input, start + offset, len - offset),
+        block_len: len - offset,
+        counter: chunk_counter,
+        flags: flags | CHUNK_END,
+    }
+}
+
+const fn parent_output(left: &CVWords, right: &CVWords) -> Output {
+    let mut block = [0; 16];
+    let mut i = 0;
+    while i < 8 {
+        block[i] = left[i];
+        block[i + 8] = right[i];
+        i += 1;
+    }
+    Output {
+        cv: *IV,
+        block,
+        block_len: BLOCK_LEN,
+        counter: 0,
+        flags: DERIVE_KEY_CONTEXT | PARENT,
+    }
+}
+
+// This follows the reference implementation: each chunk CV except the last
+// gets pushed onto a stack, merging completed subtrees as it goes, and then
+// the last chunk is merged with everything left on the stack.
+const fn hash_context(context: &[u8]) -> CVWords {
+    let mut cv_stack = [[0; 8]; MAX_DEPTH];
+    let mut stack_len = 0;
+    let mut chunk_counter = 0;
+    let mut start = 0;
+    while context.len() - start > CHUNK_LEN {
+        let mut cv = chunk_output(context, start, CHUNK_LEN, chunk_counter).chaining_value();
+        chunk_counter += 1;
+        let mut total_chunks = chunk_counter;
+        while total_chunks & 1 == 0 {
+            stack_len -= 1;
+            cv = parent_output(&cv_stack[stack_len], &cv).chaining_value();
+            total_chunks >>= 1;
+        }
+        cv_stack[stack_len] = cv;
+        stack_len += 1;
+        start += CHUNK_LEN;
+    }
+    let mut output = chunk_output(context, start, context.len() - start, chunk_counter);
+    while stack_len > 0 {
+        stack_len -= 1;
+        output = parent_output(&cv_stack[stack_len], &output.chaining_value());
+    }
+    output.root_hash()
+}
@@ -0,0 +1,543 @@
+//! Encode an input along with its BLAKE3 tree, for verified streaming.
+//!
+//! The encoding format is the same one used by the [Bao] project. An encoding
+//! starts with an 8-byte header containing the content length as a
+//! little-endian integer. That's followed by the tree in pre-order: each
+//! parent node is the 64 bytes of its two children's chaining values,
+//! followed by the encoding of its left subtree and then its right subtree.
+//! In the **combined** encoding, chunks (leaves) are the content itself, so
+//! the encoding is the content with the tree interleaved. In the
+//! **outboard** encoding, chunks are omitted, and the content is kept
+//! separately.
+//!
+//! The root node of the tree is the same as for [`hash`](crate::hash), so the
+//! regular BLAKE3 hash of the content is what a reader needs to verify an
+//! encoding. See the [`decode`](crate::decode) module.
+//!
+//! This module is gated by the `std` Cargo feature, which is enabled by
+//! default.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> std::io::Result<()> {
+//! use std::io::prelude::*;
+//!
+//! let input = vec![0xab; 10_000];
+//! let mut encoder = blake3::encode::Encoder::new(std::io::Cursor::new(Vec::new()));
+//! encoder.write_all(&input)?;
+//! let hash = encoder.finalize()?;
+//! let encoded = encoder.into_inner().into_inner();
+//!
+//! assert_eq!(hash, blake3::hash(&input));
+//! assert_eq!(encoded.len() as u128, blake3::encode::encoded_size(input.len() as u64));
+//! assert_eq!((encoded, hash), blake3::encode::encode(&input));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Slices
+//!
+//! A slice is the part of an encoding needed to verify one range of the
+//! content: the header, the chunks that overlap the range, and the parent
+//! nodes on the paths from the root down to those chunks. Each parent node
+//! carries the chaining values of the siblings of the path, so a slice is a
+//! Merkle inclusion proof for its chunks. [`extract_slice`] and
+//! [`extract_slice_outboard`] produce slices, and
+//! [`SliceDecoder`](crate::decode::SliceDecoder) verifies them.
+//!
+//! A slice always contains at least one chunk, even if the range is empty.
+//! If the range starts at or past the end of the content, the slice contains
+//! the final chunk, which authenticates the content length.
+//!
+//! [Bao]: https://github.com/oconnor663/bao
+
+use crate::{parent_node_output, CVBytes, ChunkState, Hash, CHUNK_LEN, IV, MAX_DEPTH, OUT_LEN};
+use arrayvec::ArrayVec;
+use std::cmp;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+/// The size of the length header at the start of an encoding, 8.
+pub const HEADER_SIZE: usize = 8;
+
+/// The size of a parent node in an encoding, 64.
+pub const PARENT_SIZE: usize = 2 * OUT_LEN;
+
+/// The size of the combined encoding of an input of `content_len` bytes.
+pub fn encoded_size(content_len: u64) -> u128 {
+    content_len as u128 + outboard_size(content_len)
+}
+
+/// The size of the outboard encoding of an input of `content_len` bytes.
+pub fn outboard_size(content_len: u64) -> u128 {
+    HEADER_SIZE as u128 + PARENT_SIZE as u128 * parent_count(content_len) as u128
+}
+
+// The number of chunks in an input, where the empty input is one empty chunk.
+pub(crate) fn chunk_count(content_len: u64) -> u64 {
+    if content_len == 0 {
+        return 1;
+    }
+    (content_len - 1) / CHUNK_LEN as u64 + 1
+}
+
+pub(crate) fn parent_count(content_len: u64) -> u64 {
+    chunk_count(content_len) - 1
+}
+
+// As with crate::left_len(), given a subtree larger than one chunk, return the
+// number of content bytes in its left subtree.
+pub(crate) fn left_len(content_len: u64) -> u64 {
+    debug_assert!(content_len > CHUNK_LEN as u64);
+    let full_chunks = (content_len - 1) / CHUNK_LEN as u64;
+    (full_chunks / 2 + 1).next_power_of_two() * CHUNK_LEN as u64
+}
+
+// The encoded size of a subtree, without the header.
+pub(crate) fn subtree_encoded_size(content_len: u64, outboard: bool) -> u128 {
+    let parents = PARENT_SIZE as u128 * parent_count(content_len) as u128;
+    if outboard {
+        parents
+    } else {
+        parents + content_len as u128
+    }
+}
+
+// The first and last chunk indexes (inclusive) that a slice includes. See the
+// module docs.
+pub(crate) fn slice_chunks(slice_start: u64, slice_len: u64, content_len: u64) -> (u64, u64) {
+    let last_chunk = chunk_count(content_len) - 1;
+    let first = cmp::min(slice_start / CHUNK_LEN as u64, last_chunk);
+    let slice_end = cmp::min(slice_start.saturating_add(slice_len), content_len);
+    if slice_end <= slice_start {
+        return (first, first);
+    }
+    (first, (slice_end - 1) / CHUNK_LEN as u64)
+}
+
+// Whether the subtree starting at subtree_start with subtree_len bytes of
+// content overlaps the chunks of a slice.
+pub(crate) fn subtree_in_slice(subtree_start: u64, subtree_len: u64, slice: (u64, u64)) -> bool {
+    let first_chunk = subtree_start / CHUNK_LEN as u64;
+    let end_chunk = first_chunk + chunk_count(subtree_len);
+    first_chunk <= slice.1 && slice.0 < end_chunk
+}
+
+/// Return the combined encoding of `input` and its hash.
+pub fn encode(input: impl AsRef<[u8]>) -> (Vec<u8>, Hash) {
+    encode_all(input.as_ref(), false)
+}
+
+/// Return the outboard encoding of `input` and its hash.
+pub fn outboard(input: impl AsRef<[u8]>) -> (Vec<u8>, Hash) {
+    encode_all(input.as_ref(), true)
+}
+
+fn encode_all(input: &[u8], outboard: bool) -> (Vec<u8>, Hash) {
+    let size = if outboard {
+        outboard_size(input.len() as u64)
+    } else {
+        encoded_size(input.len() as u64)
+    };
+    let output = io::Cursor::new(Vec::with_capacity(size as usize));
+    let mut encoder = Encoder::new_internal(output, outboard);
+    // Writing to a Vec can't fail.
+    encoder.write_all(input).unwrap();
+    let hash = encoder.finalize().unwrap();
+    (encoder.into_inner().into_inner(), hash)
+}
+
+/// An incremental encoder, which writes an encoding to any seekable output.
+///
+/// The encoder needs to know the whole tree before it can write the
+/// encoding in its final order, so the encoding is only complete after
+/// [`finalize`](Encoder::finalize). Until then, the encoder writes the tree in
+/// post-order (with each parent node after its children), and `finalize`
+/// rearranges it in place. The output must support reading back what was
+/// written, for example a [`File`](std::fs::File) opened for both reading and
+/// writing, or an [`io::Cursor`]. The encoding starts wherever the output is
+/// positioned when the encoder is created, and when `finalize` returns, the
+/// output is positioned at the end of the encoding.
+///
+/// If any IO error occurs, the encoding is left in an unspecified state.
+#[derive(Clone, Debug)]
+pub struct Encoder<T: Read + Write + Seek> {
+    inner: T,
+    chunk_state: ChunkState,
+    cv_stack: ArrayVec<CVBytes, MAX_DEPTH>,
+    total_len: u64,
+    outboard: bool,
+    finalized: Option<Hash>,
+}
+
+impl<T: Read + Write + Seek> Encoder<T> {
+    fn new_internal(inner: T, outboard: bool) -> Self {
+        Self {
+            inner,
+            chunk_state: ChunkState::new(IV, 0, 0, crate::platform::Platform::detect()),
+            cv_stack: ArrayVec::new(),
+            total_len: 0,
+            outboard,
+            finalized: None,
+        }
+    }
+
+    /// Construct a new `Encoder` for the combined encoding, with the tree
+    /// interleaved with the content.
+    pub fn new(inner: T) -> Self {
+        Self::new_internal(inner, false)
+    }
+
+    /// Construct a new `Encoder` for the outboard encoding, which contains
+    /// only the tree. The content isn't written to the output.
+    pub fn new_outboard(inner: T) -> Self {
+        Self::new_internal(inner, true)
+    }
+
+    // Called when the current chunk is full and more input is coming, so the
+    // chunk isn't the root. Finish the chunk, and merge it with completed
+    // subtrees on the stack, writing out each new parent node after its
+    // children. None of these parents are the root either.
+    fn finish_chunk(&mut self) -> io::Result<()> {
+        let mut cv = self.chunk_state.output().chaining_value();
+        let mut total_chunks = self.chunk_state.chunk_counter + 1;
+        while total_chunks & 1 == 0 {
+            let left = self.cv_stack.pop().unwrap();
+            cv = self.write_parent(&left, &cv)?.chaining_value();
+            total_chunks >>= 1;
+        }
+        self.cv_stack.push(cv);
+        self.chunk_state = ChunkState::new(
+            IV,
+            self.chunk_state.chunk_counter + 1,
+            0,
+            self.chunk_state.platform,
+        );
+        Ok(())
+    }
+
+    fn write_parent(&mut self, left: &CVBytes, right: &CVBytes) -> io::Result<crate::Output> {
+        self.inner.write_all(left)?;
+        self.inner.write_all(right)?;
+        Ok(parent_node_output(
+            left,
+            right,
+            IV,
+            0,
+            self.chunk_state.platform,
+        ))
+    }
+
+    /// Finish the encoding, rearrange the tree into its final order, and
+    /// return the root hash. The root hash is the same as the regular BLAKE3
+    /// hash of the content.
+    ///
+    /// This method is idempotent. Calling it again gives the same hash, but
+    /// after it's called, any further writes will fail.
+    pub fn finalize(&mut self) -> io::Result<Hash> {
+        if let Some(hash) = self.finalized {
+            return Ok(hash);
+        }
+        let output = self.chunk_state.output();
+        let hash = if self.cv_stack.is_empty() {
+            output.root_hash()
+        } else {
+            let mut cv = output.chaining_value();
+            loop {
+                let left = self.cv_stack.pop().unwrap();
+                let parent = self.write_parent(&left, &cv)?;
+                if self.cv_stack.is_empty() {
+                    break parent.root_hash();
+                }
+                cv = parent.chaining_value();
+            }
+        };
+        self.inner.write_all(&self.total_len.to_le_bytes())?;
+
+        // Now the whole post-order encoding is written, with the header at
+        // the end. Flip it into pre-order.
+        let size = if self.outboard {
+            outboard_size(self.total_len)
+        } else {
+            encoded_size(self.total_len)
+        };
+        let end = self.inner.stream_position()?;
+        let start = end - size as u64;
+        let mut flipper = Flipper {
+            inner: &mut self.inner,
+            read_pos: end - HEADER_SIZE as u64,
+            write_pos: end,
+            outboard: self.outboard,
+        };
+        flipper.flip_subtree(self.total_len)?;
+        debug_assert_eq!(flipper.read_pos, start);
+        debug_assert_eq!(flipper.write_pos, start + HEADER_SIZE as u64);
+        self.inner.seek(SeekFrom::Start(start))?;
+        self.inner.write_all(&self.total_len.to_le_bytes())?;
+        self.inner.seek(SeekFrom::Start(end))?;
+        self.finalized = Some(hash);
+        Ok(hash)
+    }
+
+    /// Return the underlying output. This should be called after
+    /// [`finalize`](Encoder::finalize). Otherwise the encoding is incomplete.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Read + Write + Seek> Write for Encoder<T> {
+    fn write(&mut self, input: &[u8]) -> io::Result<usize> {
+        if self.finalized.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "write after finalize",
+            ));
+        }
+        if input.is_empty() {
+            return Ok(0);
+        }
+        if self.chunk_state.len() == CHUNK_LEN {
+            self.finish_chunk()?;
+        }
+        let take = std::cmp::min(input.len(), CHUNK_LEN - self.chunk_state.len());
+        if !self.outboard {
+            self.inner.write_all(&input[..take])?;
+        }
+        self.chunk_state.update(&input[..take]);
+        self.total_len += take as u64;
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Extract the slice of a combined encoding that covers `slice_len` bytes of
+/// content starting at `slice_start`, and write it to `output`. See the
+/// [module docs](self#slices).
+///
+/// The encoding must start at offset 0 of `encoded`.
+pub fn extract_slice(
+    encoded: impl Read + Seek,
+    slice_start: u64,
+    slice_len: u64,
+    output: impl Write,
+) -> io::Result<()> {
+    let mut encoded = encoded;
+    SliceExtractor {
+        tree: &mut encoded,
+        content: None,
+        output,
+    }
+    .extract(slice_start, slice_len)
+}
+
+/// As [`extract_slice`], but for content stored separately from its outboard
+/// encoding. The slice is the same as it would be for the combined encoding.
+///
+/// The content and the outboard encoding must both start at offset 0.
+pub fn extract_slice_outboard(
+    content: impl Read + Seek,
+    outboard: impl Read + Seek,
+    slice_start: u64,
+    slice_len: u64,
+    output: impl Write,
+) -> io::Result<()> {
+    let (mut content, mut outboard) = (content, outboard);
+    SliceExtractor {
+        tree: &mut outboard,
+        content: Some(&mut content),
+        output,
+    }
+    .extract(slice_start, slice_len)
+}
+
+trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+struct SliceExtractor<'a, W: Write> {
+    // The combined encoding, or the outboard encoding if there's content.
+    tree: &'a mut dyn ReadSeek,
+    content: Option<&'a mut dyn ReadSeek>,
+    output: W,
+}
+
+impl<W: Write> SliceExtractor<'_, W> {
+    fn extract(&mut self, slice_start: u64, slice_len: u64) -> io::Result<()> {
+        let mut header = [0; HEADER_SIZE];
+        self.tree.seek(SeekFrom::Start(0))?;
+        self.tree.read_exact(&mut header)?;
+        self.output.write_all(&header)?;
+        let content_len = u64::from_le_bytes(header);
+        let slice = slice_chunks(slice_start, slice_len, content_len);
+        self.extract_subtree(0, content_len, HEADER_SIZE as u128, slice)
+    }
+
+    fn extract_subtree(
+        &mut self,
+        start: u64,
+        len: u64,
+        encoded_offset: u128,
+        slice: (u64, u64),
+    ) -> io::Result<()> {
+        if !subtree_in_slice(start, len, slice) {
+            return Ok(());
+        }
+        let outboard = self.content.is_some();
+        let encoded_offset_u64 = u64::try_from(encoded_offset)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "encoding too long"))?;
+        if len <= CHUNK_LEN as u64 {
+            let mut chunk = [0; CHUNK_LEN];
+            let chunk = &mut chunk[..len as usize];
+            match &mut self.content {
+                Some(content) => {
+                    content.seek(SeekFrom::Start(start))?;
+                    content.read_exact(chunk)?;
+                }
+                None => {
+                    self.tree.seek(SeekFrom::Start(encoded_offset_u64))?;
+                    self.tree.read_exact(chunk)?;
+                }
+            }
+            return self.output.write_all(chunk);
+        }
+        let mut parent = [0; PARENT_SIZE];
+        self.tree.seek(SeekFrom::Start(encoded_offset_u64))?;
+        self.tree.read_exact(&mut parent)?;
+        self.output.write_all(&parent)?;
+        let left_len = left_len(len);
+        let left_offset = encoded_offset + PARENT_SIZE as u128;
+        let right_offset = left_offset + subtree_encoded_size(left_len, outboard);
+        self.extract_subtree(start, left_len, left_offset, slice)?;
+        self.extract_subtree(start + left_len, len - left_len, right_offset, slice)
+    }
+}
+
+// Rearranges a post-order encoding into pre-order, in place. We walk the
+// post-order encoding backwards, which visits each parent node first, then
+// its right subtree, then its left subtree. That's exactly the reverse of
+// pre-order, so we write everything backwards from the end, except that each
+// parent is held in memory until its children have been written. The write
+// position never falls below the read position, because the only bytes we've
+// read but not written are the parents we're holding.
+struct Flipper<'a, T: Read + Write + Seek> {
+    inner: &'a mut T,
+    read_pos: u64,
+    write_pos: u64,
+    outboard: bool,
+}
+
+impl<T: Read + Write + Seek> Flipper<'_, T> {
+    fn flip_subtree(&mut self, content_len: u64) -> io::Result<()> {
+        if content_len <= CHUNK_LEN as u64 {
+            if !self.outboard {
+                let mut chunk = [0; CHUNK_LEN];
+                let chunk = &mut chunk[..content_len as usize];
+                self.read_pos -= content_len;
+                self.inner.seek(SeekFrom::Start(self.read_pos))?;
+                self.inner.read_exact(chunk)?;
+                self.write_pos -= content_len;
+                self.inner.seek(SeekFrom::Start(self.write_pos))?;
+                self.inner.write_all(chunk)?;
+            }
+            return Ok(());
+        }
+        let mut parent = [0; PARENT_SIZE];
+        self.read_pos -= PARENT_SIZE as u64;
+        self.inner.seek(SeekFrom::Start(self.read_pos))?;
+        self.inner.read_exact(&mut parent)?;
+        let left_len = left_len(content_len);
+        self.flip_subtree(content_len - left_len)?;
+        self.flip_subtree(left_len)?;
+        self.write_pos -= PARENT_SIZE as u64;
+        self.inner.seek(SeekFrom::Start(self.write_pos))?;
+        self.inner.write_all(&parent)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{paint_test_input, TEST_CASES};
+
+    // A straightforward recursive pre-order encoder, to compare against.
+    fn encode_recursive(
+        input: &[u8],
+        chunk_counter: u64,
+        outboard: bool,
+        out: &mut Vec<u8>,
+    ) -> CVBytes {
+        if input.len() <= CHUNK_LEN {
+            if !outboard {
+                out.extend_from_slice(input);
+            }
+            return crate::guts::ChunkState::new(chunk_counter)
+                .update(input)
+                .finalize(false)
+                .into();
+        }
+        let parent_start = out.len();
+        out.extend_from_slice(&[0; PARENT_SIZE]);
+        let left_len = left_len(input.len() as u64) as usize;
+        let left_cv = encode_recursive(&input[..left_len], chunk_counter, outboard, out);
+        let right_chunk_counter = chunk_counter + (left_len / CHUNK_LEN) as u64;
+        let right_cv = encode_recursive(&input[left_len..], right_chunk_counter, outboard, out);
+        out[parent_start..][..OUT_LEN].copy_from_slice(&left_cv);
+        out[parent_start + OUT_LEN..][..OUT_LEN].copy_from_slice(&right_cv);
+        crate::guts::parent_cv(&left_cv.into(), &right_cv.into(), false).into()
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut input = vec![0; crate::test::TEST_CASES_MAX];
+        paint_test_input(&mut input);
+        for &case in TEST_CASES {
+            let input = &input[..case];
+            let expected_hash = crate::hash(input);
+            for &outboard in &[false, true] {
+                let mut expected = (input.len() as u64).to_le_bytes().to_vec();
+                encode_recursive(input, 0, outboard, &mut expected);
+                let (encoded, hash) = if outboard {
+                    super::outboard(input)
+                } else {
+                    super::encode(input)
+                };
+                assert_eq!(hash, expected_hash);
+                assert_eq!(encoded, expected, "case {} outboard {}", case, outboard);
+                let size = if outboard {
+                    outboard_size(case as u64)
+                } else {
+                    encoded_size(case as u64)
+                };
+                assert_eq!(encoded.len() as u128, size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_encoder_writes_and_offset() {
+        let mut input = vec![0; 5 * CHUNK_LEN + 7];
+        paint_test_input(&mut input);
+        let (expected, expected_hash) = encode(&input);
+
+        // Start the encoding partway into the output, and write in small,
+        // uneven pieces.
+        let mut output = io::Cursor::new(vec![0xff; 3]);
+        output.seek(SeekFrom::End(0)).unwrap();
+        let mut encoder = Encoder::new(output);
+        for piece in input.chunks(100) {
+            encoder.write_all(piece).unwrap();
+        }
+        assert_eq!(encoder.finalize().unwrap(), expected_hash);
+        assert_eq!(encoder.finalize().unwrap(), expected_hash);
+        assert!(encoder.write(b"more").is_err());
+        let output = encoder.into_inner();
+        assert_eq!(output.position(), 3 + expected.len() as u64);
+        assert_eq!(&output.get_ref()[..3], &[0xff; 3]);
+        assert_eq!(&output.get_ref()[3..], &expected[..]);
+    }
+}
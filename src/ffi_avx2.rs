@@ -3,6 +3,21 @@ use crate::{CVWords, IncrementCounter, BLOCK_LEN, OUT_LEN};
 // Note that there is no AVX2 implementation of compress_in_place or
 // compress_xof.
 
+// The assembly implementation doesn't have a multi-block XOF kernel, so this
+// uses the Rust intrinsics implementation, which computes eight blocks at once.
+//
+// Unsafe because this may only be called on platforms supporting AVX2.
+pub unsafe fn xof_many(
+    cv: &CVWords,
+    block: &[u8; BLOCK_LEN],
+    block_len: u8,
+    counter: u64,
+    flags: u8,
+    out: &mut [u8],
+) {
+    crate::rust_avx2::xof_many(cv, block, block_len, counter, flags, out)
+}
+
 // Unsafe because this may only be called on platforms supporting AVX2.
 pub unsafe fn hash_many<const N: usize>(
     inputs: &[&[u8; N]],
@@ -60,4 +75,12 @@ mod test {
         }
         crate::test::test_hash_many_fn(hash_many, hash_many);
     }
+
+    #[test]
+    fn test_xof_many() {
+        if !crate::platform::avx2_detected() {
+            return;
+        }
+        crate::test::test_xof_many_fn(xof_many);
+    }
 }
@@ -31,6 +31,21 @@ pub unsafe fn compress_xof(
     out
 }
 
+// The assembly implementations don't have a multi-block XOF kernel, so this
+// uses the Rust intrinsics implementation, which computes four blocks at once.
+//
+// Unsafe because this may only be called on platforms supporting SSE4.1.
+pub unsafe fn xof_many(
+    cv: &CVWords,
+    block: &[u8; BLOCK_LEN],
+    block_len: u8,
+    counter: u64,
+    flags: u8,
+    out: &mut [u8],
+) {
+    crate::rust_sse41::xof_many(cv, block, block_len, counter, flags, out)
+}
+
 // Unsafe because this may only be called on platforms supporting SSE4.1.
 pub unsafe fn hash_many<const N: usize>(
     inputs: &[&[u8; N]],
@@ -111,4 +126,12 @@ mod test {
         }
         crate::test::test_hash_many_fn(hash_many, hash_many);
     }
+
+    #[test]
+    fn test_xof_many() {
+        if !crate::platform::sse41_detected() {
+            return;
+        }
+        crate::test::test_xof_many_fn(xof_many);
+    }
 }
@@ -1,40 +1,228 @@
-//! This undocumented and unstable module is for use cases like the `bao` crate,
-//! which need to traverse the BLAKE3 Merkle tree and work with chunk and parent
-//! chaining values directly. There might be breaking changes to this module
-//! between patch versions.
+//! Low-level access to chunks, parent nodes, and the compression function.
 //!
-//! We could stabilize something like this module in the future. If you have a
-//! use case for it, please let us know by filing a GitHub issue.
+//! This module is for use cases like the `bao` crate that traverse the BLAKE3
+//! Merkle tree and work with chunk and parent chaining values directly. Most
+//! callers want [`Hasher`](crate::Hasher) or the [`subtree`](crate::subtree)
+//! module instead. Everything here is part of the stable API and follows
+//! semver like the rest of the crate.
+//!
+//! Nothing in this module stops you from building an incorrect tree. The
+//! chunk counters, the `is_root` arguments, and the order of parent nodes
+//! have to follow the BLAKE3 spec, or the results won't match [`hash`].
+//!
+//! # Example
+//!
+//! ```
+//! use blake3::guts::{parent_cv, ChunkState, CHUNK_LEN};
+//!
+//! // Hash a three-chunk input by hand. The left subtree covers the largest
+//! // power-of-two number of chunks that's less than the total.
+//! let input = [0x42; 2 * CHUNK_LEN + 1];
+//! let chunk0 = ChunkState::new(0).update(&input[..CHUNK_LEN]).finalize(false);
+//! let chunk1 = ChunkState::new(1).update(&input[CHUNK_LEN..][..CHUNK_LEN]).finalize(false);
+//! let chunk2 = ChunkState::new(2).update(&input[2 * CHUNK_LEN..]).finalize(false);
+//! let left = parent_cv(&chunk0, &chunk1, false);
+//! assert_eq!(parent_cv(&left, &chunk2, true), blake3::hash(&input));
+//! ```
+//!
+//! [`hash`]: crate::hash
+
+use crate::platform::{Platform, MAX_SIMD_DEGREE};
+use crate::subtree::Mode;
+use arrayref::array_ref;
+use arrayvec::ArrayVec;
 
+/// The number of bytes in a block, the input of one call to [`compress`].
 pub const BLOCK_LEN: usize = 64;
+
+/// The number of bytes in a chunk, the leaf node of the tree.
 pub const CHUNK_LEN: usize = 1024;
 
+/// The BLAKE3 IV as little-endian bytes. This is the key for the regular hash
+/// function, and the input chaining value of the first block of every chunk.
+pub const IV: [u8; 32] = {
+    let mut bytes = [0; 32];
+    let mut i = 0;
+    while i < 8 {
+        let word = crate::IV[i].to_le_bytes();
+        bytes[4 * i] = word[0];
+        bytes[4 * i + 1] = word[1];
+        bytes[4 * i + 2] = word[2];
+        bytes[4 * i + 3] = word[3];
+        i += 1;
+    }
+    bytes
+};
+
+/// The domain separation flags for [`compress`]. Combine them with `|`.
+///
+/// # Example
+///
+/// ```
+/// use blake3::guts::Flags;
+///
+/// let flags = Flags::CHUNK_START | Flags::CHUNK_END;
+/// assert!(flags.contains(Flags::CHUNK_END));
+/// assert!(!flags.contains(Flags::ROOT));
+/// assert_eq!(flags.bits(), 0b11);
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Flags(u8);
+
+impl Flags {
+    /// Set on the first block of each chunk.
+    pub const CHUNK_START: Self = Self(crate::CHUNK_START);
+    /// Set on the last block of each chunk.
+    pub const CHUNK_END: Self = Self(crate::CHUNK_END);
+    /// Set on parent nodes.
+    pub const PARENT: Self = Self(crate::PARENT);
+    /// Set on the root node, for the root hash and extended output.
+    pub const ROOT: Self = Self(crate::ROOT);
+    /// Set on every block in the keyed hash mode.
+    pub const KEYED_HASH: Self = Self(crate::KEYED_HASH);
+    /// Set on every block when hashing the context string of the key
+    /// derivation mode.
+    pub const DERIVE_KEY_CONTEXT: Self = Self(crate::DERIVE_KEY_CONTEXT);
+    /// Set on every block when hashing the key material of the key derivation
+    /// mode.
+    pub const DERIVE_KEY_MATERIAL: Self = Self(crate::DERIVE_KEY_MATERIAL);
+
+    /// No flags set.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// The flags as the byte that the compression function takes.
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Whether every flag in `other` is also set in `self`.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for Flags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl core::ops::BitOrAssign for Flags {
+    fn bitor_assign(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+}
+
+/// The BLAKE3 compression function, using the fastest backend the CPU
+/// supports.
+///
+/// The first 32 bytes of the result are the output chaining value. When
+/// [`Flags::ROOT`] is set, all 64 bytes are a block of extended output, and
+/// `counter` is the index of that output block. Otherwise `counter` is the
+/// chunk counter, which is zero for parent nodes. The block must be
+/// zero-padded past `block_len`.
+///
+/// # Panics
+///
+/// Panics if `block_len` is greater than [`BLOCK_LEN`].
+///
+/// # Example
+///
+/// ```
+/// use blake3::guts::{compress, Flags, BLOCK_LEN, IV};
+///
+/// // An input of one block is a single chunk, which is also the root.
+/// let mut block = [0; BLOCK_LEN];
+/// block[..3].copy_from_slice(b"foo");
+/// let flags = Flags::CHUNK_START | Flags::CHUNK_END | Flags::ROOT;
+/// let out = compress(&IV, &block, 3, 0, flags);
+/// assert_eq!(&out[..32], blake3::hash(b"foo").as_bytes());
+/// ```
+pub fn compress(
+    cv: &[u8; 32],
+    block: &[u8; BLOCK_LEN],
+    block_len: u8,
+    counter: u64,
+    flags: Flags,
+) -> [u8; 2 * crate::OUT_LEN] {
+    assert!(block_len as usize <= BLOCK_LEN, "block_len too long");
+    let cv_words = crate::platform::words_from_le_bytes_32(cv);
+    Platform::detect().compress_xof(&cv_words, block, block_len, counter, flags.0)
+}
+
+/// The state of one chunk of input, for computing chunk chaining values one
+/// at a time.
+///
+/// A chunk is up to [`CHUNK_LEN`] bytes of input, and its chunk counter is its
+/// index in the input. Set `is_root` only when the chunk is the whole input.
+/// To compute the chaining values of many whole chunks at once, see
+/// [`chunk_cvs`].
+///
+/// # Example
+///
+/// ```
+/// use blake3::guts::{parent_cv, ChunkState, CHUNK_LEN};
+///
+/// let input = [0x42; CHUNK_LEN + 3];
+/// let left = ChunkState::new(0).update(&input[..CHUNK_LEN]).finalize(false);
+/// let right = ChunkState::new(1).update(&input[CHUNK_LEN..]).finalize(false);
+/// assert_eq!(parent_cv(&left, &right, true), blake3::hash(&input));
+/// ```
 #[derive(Clone, Debug)]
 pub struct ChunkState(crate::ChunkState);
 
 impl ChunkState {
-    // Currently this type only supports the regular hash mode. If an
-    // incremental user needs keyed_hash or derive_key, we can add that.
+    /// Start the chunk with counter `chunk_counter`, for the regular hash
+    /// function. See [`with_mode`](ChunkState::with_mode) for the keyed hash
+    /// and key derivation functions.
     pub fn new(chunk_counter: u64) -> Self {
+        Self::with_mode(&Mode::hash(), chunk_counter)
+    }
+
+    /// Start the chunk with counter `chunk_counter`, in any of the hashing
+    /// modes. Build the [`Mode`] once and reuse it, because
+    /// [`Mode::derive_key`] hashes the context string. Every chunk and parent
+    /// node of an input has to use the same mode.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use blake3::guts::ChunkState;
+    /// use blake3::subtree::Mode;
+    ///
+    /// let key = [42; 32];
+    /// let mode = Mode::keyed_hash(&key);
+    /// let hash = ChunkState::with_mode(&mode, 0).update(b"foo").finalize(true);
+    /// assert_eq!(hash, blake3::keyed_hash(&key, b"foo"));
+    /// ```
+    pub fn with_mode(mode: &Mode, chunk_counter: u64) -> Self {
         Self(crate::ChunkState::new(
-            crate::IV,
+            &mode.key,
             chunk_counter,
-            0,
-            crate::platform::Platform::detect(),
+            mode.flags,
+            Platform::detect(),
         ))
     }
 
+    /// The number of bytes of input added to the chunk so far.
     #[inline]
     pub fn len(&self) -> usize {
         self.0.len()
     }
 
+    /// Add input to the chunk. Adding more than [`CHUNK_LEN`] bytes in total
+    /// is a logic error, and debug builds panic.
     #[inline]
     pub fn update(&mut self, input: &[u8]) -> &mut Self {
         self.0.update(input);
         self
     }
 
+    /// The chaining value of the chunk, or the root hash if `is_root` is set.
     pub fn finalize(&self, is_root: bool) -> crate::Hash {
         let output = self.0.output();
         if is_root {
@@ -43,21 +231,46 @@ impl ChunkState {
             output.chaining_value().into()
         }
     }
+
+    /// As [`finalize`](ChunkState::finalize) with `is_root` set, but for
+    /// extended output. The first 32 bytes of output are the root hash.
+    pub fn finalize_xof(&self) -> crate::OutputReader {
+        crate::OutputReader::new(self.0.output())
+    }
 }
 
-// As above, this currently assumes the regular hash mode. If an incremental
-// user needs keyed_hash or derive_key, we can add that.
+/// The chaining value of the parent node of two children, for the regular
+/// hash function, or the root hash if `is_root` is set. See
+/// [`parent_cv_with_mode`] for the keyed hash and key derivation functions,
+/// and [`parent_cvs`] to compute many parents at once.
 pub fn parent_cv(
     left_child: &crate::Hash,
     right_child: &crate::Hash,
     is_root: bool,
+) -> crate::Hash {
+    parent_cv_with_mode(left_child, right_child, is_root, &Mode::hash())
+}
+
+/// As [`parent_cv`] with `is_root` set, but for extended output. The first 32
+/// bytes of output are the root hash.
+pub fn parent_xof(left_child: &crate::Hash, right_child: &crate::Hash) -> crate::OutputReader {
+    parent_xof_with_mode(left_child, right_child, &Mode::hash())
+}
+
+/// As [`parent_cv`], but in any of the hashing modes. The children must have
+/// been hashed with the same mode.
+pub fn parent_cv_with_mode(
+    left_child: &crate::Hash,
+    right_child: &crate::Hash,
+    is_root: bool,
+    mode: &Mode,
 ) -> crate::Hash {
     let output = crate::parent_node_output(
         left_child.as_bytes(),
         right_child.as_bytes(),
-        crate::IV,
-        0,
-        crate::platform::Platform::detect(),
+        &mode.key,
+        mode.flags,
+        Platform::detect(),
     );
     if is_root {
         output.root_hash()
@@ -66,6 +279,152 @@ pub fn parent_cv(
     }
 }
 
+/// As [`parent_xof`], but in any of the hashing modes. The children must have
+/// been hashed with the same mode.
+pub fn parent_xof_with_mode(
+    left_child: &crate::Hash,
+    right_child: &crate::Hash,
+    mode: &Mode,
+) -> crate::OutputReader {
+    crate::OutputReader::new(crate::parent_node_output(
+        left_child.as_bytes(),
+        right_child.as_bytes(),
+        &mode.key,
+        mode.flags,
+        Platform::detect(),
+    ))
+}
+
+/// Compute the chaining values of a run of chunks, for the regular hash
+/// function, as many at a time as the SIMD degree of the platform allows.
+///
+/// The first chunk has counter `chunk_counter`. Only the last chunk may be
+/// shorter than [`CHUNK_LEN`]. None of the results are root hashes, so a
+/// single chunk that is the whole input needs [`ChunkState`] instead. See
+/// [`chunk_cvs_with_mode`] for the keyed hash and key derivation functions.
+///
+/// # Panics
+///
+/// Panics if `out` doesn't have exactly one element for each chunk.
+///
+/// # Example
+///
+/// ```
+/// use blake3::guts::{chunk_cvs, parent_cv, CHUNK_LEN};
+///
+/// let input = [0x42; 2 * CHUNK_LEN];
+/// let mut cvs = [blake3::Hash::from([0; 32]); 2];
+/// chunk_cvs(&input, 0, &mut cvs);
+/// assert_eq!(parent_cv(&cvs[0], &cvs[1], true), blake3::hash(&input));
+/// ```
+pub fn chunk_cvs(input: &[u8], chunk_counter: u64, out: &mut [crate::Hash]) {
+    chunk_cvs_with_mode(input, chunk_counter, &Mode::hash(), out)
+}
+
+/// As [`chunk_cvs`], but in any of the hashing modes.
+///
+/// # Panics
+///
+/// Panics if `out` doesn't have exactly one element for each chunk.
+pub fn chunk_cvs_with_mode(input: &[u8], chunk_counter: u64, mode: &Mode, out: &mut [crate::Hash]) {
+    chunk_cvs_inner(input, chunk_counter, mode, Platform::detect(), out)
+}
+
+fn chunk_cvs_inner(
+    input: &[u8],
+    chunk_counter: u64,
+    mode: &Mode,
+    platform: Platform,
+    out: &mut [crate::Hash],
+) {
+    let num_chunks = if input.is_empty() {
+        0
+    } else {
+        (input.len() - 1) / CHUNK_LEN + 1
+    };
+    assert_eq!(out.len(), num_chunks, "wrong number of output CVs");
+    let mut cvs = [0; MAX_SIMD_DEGREE * crate::OUT_LEN];
+    let batches = input.chunks(MAX_SIMD_DEGREE * CHUNK_LEN);
+    for (i, (batch, out)) in batches.zip(out.chunks_mut(MAX_SIMD_DEGREE)).enumerate() {
+        let counter = chunk_counter + (i * MAX_SIMD_DEGREE) as u64;
+        crate::compress_chunks_parallel(batch, &mode.key, counter, mode.flags, platform, &mut cvs);
+        for (cv, bytes) in out.iter_mut().zip(cvs.chunks_exact(crate::OUT_LEN)) {
+            *cv = (*array_ref!(bytes, 0, crate::OUT_LEN)).into();
+        }
+    }
+}
+
+/// Compute the chaining values of parent nodes from pairs of child chaining
+/// values, for the regular hash function, as many at a time as the SIMD
+/// degree of the platform allows.
+///
+/// None of the results are root hashes, so the root node needs [`parent_cv`]
+/// instead. See [`parent_cvs_with_mode`] for the keyed hash and key
+/// derivation functions.
+///
+/// # Panics
+///
+/// Panics if `out` doesn't have exactly one element for each pair.
+///
+/// # Example
+///
+/// ```
+/// use blake3::guts::{chunk_cvs, parent_cv, parent_cvs, CHUNK_LEN};
+///
+/// let input = [0x42; 4 * CHUNK_LEN];
+/// let mut chunks = [blake3::Hash::from([0; 32]); 4];
+/// chunk_cvs(&input, 0, &mut chunks);
+/// let mut parents = [blake3::Hash::from([0; 32]); 2];
+/// parent_cvs(&[[chunks[0], chunks[1]], [chunks[2], chunks[3]]], &mut parents);
+/// assert_eq!(parent_cv(&parents[0], &parents[1], true), blake3::hash(&input));
+/// ```
+pub fn parent_cvs(children: &[[crate::Hash; 2]], out: &mut [crate::Hash]) {
+    parent_cvs_with_mode(children, &Mode::hash(), out)
+}
+
+/// As [`parent_cvs`], but in any of the hashing modes. The children must have
+/// been hashed with the same mode.
+///
+/// # Panics
+///
+/// Panics if `out` doesn't have exactly one element for each pair.
+pub fn parent_cvs_with_mode(children: &[[crate::Hash; 2]], mode: &Mode, out: &mut [crate::Hash]) {
+    parent_cvs_inner(children, mode, Platform::detect(), out)
+}
+
+fn parent_cvs_inner(
+    children: &[[crate::Hash; 2]],
+    mode: &Mode,
+    platform: Platform,
+    out: &mut [crate::Hash],
+) {
+    assert_eq!(out.len(), children.len(), "wrong number of output CVs");
+    let mut blocks = [[0; BLOCK_LEN]; MAX_SIMD_DEGREE];
+    let mut cvs = [0; MAX_SIMD_DEGREE * crate::OUT_LEN];
+    let batches = children.chunks(MAX_SIMD_DEGREE);
+    for (batch, out) in batches.zip(out.chunks_mut(MAX_SIMD_DEGREE)) {
+        let mut parents = ArrayVec::<&[u8; BLOCK_LEN], MAX_SIMD_DEGREE>::new();
+        for ([left, right], block) in batch.iter().zip(&mut blocks) {
+            block[..32].copy_from_slice(left.as_bytes());
+            block[32..].copy_from_slice(right.as_bytes());
+            parents.push(block);
+        }
+        platform.hash_many(
+            &parents,
+            &mode.key,
+            0, // Parents always use counter 0.
+            crate::IncrementCounter::No,
+            mode.flags | crate::PARENT,
+            0, // Parents have no start flags.
+            0, // Parents have no end flags.
+            &mut cvs,
+        );
+        for (cv, bytes) in out.iter_mut().zip(cvs.chunks_exact(crate::OUT_LEN)) {
+            *cv = (*array_ref!(bytes, 0, crate::OUT_LEN)).into();
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -98,4 +457,237 @@ mod test {
         let root = parent_cv(&parent, &chunk2_cv, true);
         assert_eq!(hasher.finalize(), root);
     }
+
+    #[test]
+    fn test_modes() {
+        let context = "guts test context";
+        let modes = [
+            (Mode::hash(), crate::Hasher::new()),
+            (
+                Mode::keyed_hash(&crate::test::TEST_KEY),
+                crate::Hasher::new_keyed(&crate::test::TEST_KEY),
+            ),
+            (
+                Mode::derive_key(context),
+                crate::Hasher::new_derive_key(context),
+            ),
+        ];
+        for (mode, hasher) in &modes {
+            // A single chunk is the root.
+            let mut hasher1 = hasher.clone();
+            hasher1.update(b"foo");
+            let root = ChunkState::with_mode(mode, 0).update(b"foo").finalize(true);
+            assert_eq!(hasher1.finalize(), root);
+
+            // Two chunks and a parent.
+            let mut hasher2 = hasher.clone();
+            let buf = [0x42; CHUNK_LEN];
+            hasher2.update(&buf).update(b"bar");
+            let chunk0_cv = ChunkState::with_mode(mode, 0).update(&buf).finalize(false);
+            let chunk1_cv = ChunkState::with_mode(mode, 1)
+                .update(b"bar")
+                .finalize(false);
+            let root = parent_cv_with_mode(&chunk0_cv, &chunk1_cv, true, mode);
+            assert_eq!(hasher2.finalize(), root);
+        }
+    }
+
+    #[test]
+    fn test_batches() {
+        let mut input = [0; 40 * CHUNK_LEN];
+        crate::test::paint_test_input(&mut input);
+        let modes = [
+            Mode::hash(),
+            Mode::keyed_hash(&crate::test::TEST_KEY),
+            Mode::derive_key("guts test context"),
+        ];
+        let platforms = [Platform::portable(), Platform::detect()];
+        let mut expected = [crate::Hash::from([0; 32]); 40];
+        let mut out = [crate::Hash::from([0; 32]); 40];
+        for mode in &modes {
+            for &platform in &platforms {
+                let cases = [
+                    (0, 0),
+                    (1, 1),
+                    (CHUNK_LEN, 1),
+                    (17 * CHUNK_LEN - 1, 17),
+                    (40 * CHUNK_LEN, 40),
+                ];
+                for &(len, num_chunks) in &cases {
+                    for (i, chunk) in input[..len].chunks(CHUNK_LEN).enumerate() {
+                        expected[i] = ChunkState::with_mode(mode, 5 + i as u64)
+                            .update(chunk)
+                            .finalize(false);
+                    }
+                    chunk_cvs_inner(&input[..len], 5, mode, platform, &mut out[..num_chunks]);
+                    assert_eq!(expected[..num_chunks], out[..num_chunks]);
+                }
+
+                let mut children = [[crate::Hash::from([0; 32]); 2]; 20];
+                let mut expected_parents = [crate::Hash::from([0; 32]); 20];
+                for i in 0..20 {
+                    children[i] = [expected[2 * i], expected[2 * i + 1]];
+                    let [left, right] = &children[i];
+                    expected_parents[i] = parent_cv_with_mode(left, right, false, mode);
+                }
+                for num_parents in 0..=20 {
+                    parent_cvs_inner(
+                        &children[..num_parents],
+                        mode,
+                        platform,
+                        &mut out[..num_parents],
+                    );
+                    assert_eq!(expected_parents[..num_parents], out[..num_parents]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_chunk_cvs_wrong_out_len() {
+        let mut out = [crate::Hash::from([0; 32]); 2];
+        chunk_cvs(&[0; CHUNK_LEN + 1], 0, &mut out[..1]);
+    }
+
+    // Return the inputs to the last compression of a chunk, using compress()
+    // for the others.
+    fn last_block(
+        key: &[u8; 32],
+        counter: u64,
+        mode_flags: Flags,
+        chunk: &[u8],
+    ) -> ([u8; 32], [u8; BLOCK_LEN], u8, Flags) {
+        let mut cv = *key;
+        let mut flags = mode_flags | Flags::CHUNK_START;
+        let mut offset = 0;
+        while chunk.len() - offset > BLOCK_LEN {
+            let block = array_ref!(chunk, offset, BLOCK_LEN);
+            let out = compress(&cv, block, BLOCK_LEN as u8, counter, flags);
+            cv = *array_ref!(out, 0, 32);
+            flags = mode_flags;
+            offset += BLOCK_LEN;
+        }
+        let mut block = [0; BLOCK_LEN];
+        block[..chunk.len() - offset].copy_from_slice(&chunk[offset..]);
+        let block_len = (chunk.len() - offset) as u8;
+        (cv, block, block_len, flags | Flags::CHUNK_END)
+    }
+
+    // Hash an input of up to two chunks with compress(), and return two blocks
+    // of extended output.
+    fn hash_with_compress(key: &[u8; 32], mode_flags: Flags, input: &[u8]) -> [u8; 128] {
+        let (cv, block, block_len, flags) = if input.len() <= CHUNK_LEN {
+            last_block(key, 0, mode_flags, input)
+        } else {
+            let mut block = [0; BLOCK_LEN];
+            for (i, chunk) in input.chunks(CHUNK_LEN).enumerate() {
+                let (cv, last, last_len, flags) = last_block(key, i as u64, mode_flags, chunk);
+                let out = compress(&cv, &last, last_len, i as u64, flags);
+                block[32 * i..][..32].copy_from_slice(&out[..32]);
+            }
+            (*key, block, BLOCK_LEN as u8, mode_flags | Flags::PARENT)
+        };
+        let mut out = [0; 128];
+        for counter in 0..2 {
+            let output = compress(&cv, &block, block_len, counter, flags | Flags::ROOT);
+            out[64 * counter as usize..][..64].copy_from_slice(&output);
+        }
+        out
+    }
+
+    #[test]
+    fn test_compress() {
+        let context = "guts test context";
+        let context_key_out =
+            hash_with_compress(&IV, Flags::DERIVE_KEY_CONTEXT, context.as_bytes());
+        let context_key = array_ref!(context_key_out, 0, 32);
+        let mut input = [0; 2 * CHUNK_LEN];
+        crate::test::paint_test_input(&mut input);
+        for &len in &[0, 1, 63, 64, 65, 1023, 1024, 1025, 2048] {
+            let input = &input[..len];
+            let mut expected = [0; 128];
+
+            let mut reference = reference_impl::Hasher::new();
+            reference.update(input);
+            reference.finalize(&mut expected);
+            assert_eq!(expected, hash_with_compress(&IV, Flags::empty(), input));
+
+            let key = &crate::test::TEST_KEY;
+            let mut reference = reference_impl::Hasher::new_keyed(key);
+            reference.update(input);
+            reference.finalize(&mut expected);
+            assert_eq!(expected, hash_with_compress(key, Flags::KEYED_HASH, input));
+
+            let mut reference = reference_impl::Hasher::new_derive_key(context);
+            reference.update(input);
+            reference.finalize(&mut expected);
+            let out = hash_with_compress(context_key, Flags::DERIVE_KEY_MATERIAL, input);
+            assert_eq!(expected, out);
+        }
+    }
+
+    #[test]
+    fn test_flags() {
+        let flags = Flags::CHUNK_START | Flags::CHUNK_END;
+        assert_eq!(flags.bits(), 3);
+        assert!(flags.contains(Flags::CHUNK_END));
+        assert!(!flags.contains(Flags::ROOT));
+        assert!(flags.contains(Flags::empty()));
+        let mut all = Flags::empty();
+        for &flag in &[
+            Flags::CHUNK_START,
+            Flags::CHUNK_END,
+            Flags::PARENT,
+            Flags::ROOT,
+            Flags::KEYED_HASH,
+            Flags::DERIVE_KEY_CONTEXT,
+            Flags::DERIVE_KEY_MATERIAL,
+        ] {
+            assert_eq!(flag.bits().count_ones(), 1);
+            assert!(!all.contains(flag));
+            all |= flag;
+        }
+        assert_eq!(all.bits(), 0x7f);
+        assert_eq!(crate::platform::words_from_le_bytes_32(&IV), *crate::IV);
+    }
+
+    #[test]
+    fn test_xof() {
+        let mut expected = [0; 200];
+        let mut out = [0; 200];
+
+        // A single chunk.
+        crate::Hasher::new()
+            .update(b"foo")
+            .finalize_xof()
+            .fill(&mut expected);
+        ChunkState::new(0)
+            .update(b"foo")
+            .finalize_xof()
+            .fill(&mut out);
+        assert_eq!(expected, out);
+
+        // A parent of two chunks.
+        let buf = [0x42; CHUNK_LEN];
+        let mut hasher = crate::Hasher::new();
+        hasher.update(&buf).update(b"bar");
+        hasher.finalize_xof().fill(&mut expected);
+        let chunk0_cv = ChunkState::new(0).update(&buf).finalize(false);
+        let chunk1_cv = ChunkState::new(1).update(b"bar").finalize(false);
+        parent_xof(&chunk0_cv, &chunk1_cv).fill(&mut out);
+        assert_eq!(expected, out);
+
+        // And in another mode.
+        let mode = Mode::keyed_hash(&crate::test::TEST_KEY);
+        let mut hasher = crate::Hasher::new_keyed(&crate::test::TEST_KEY);
+        hasher.update(&buf).update(b"bar");
+        hasher.finalize_xof().fill(&mut expected);
+        let chunk0_cv = ChunkState::with_mode(&mode, 0).update(&buf).finalize(false);
+        let chunk1_cv = ChunkState::with_mode(&mode, 1)
+            .update(b"bar")
+            .finalize(false);
+        parent_xof_with_mode(&chunk0_cv, &chunk1_cv, &mode).fill(&mut out);
+        assert_eq!(expected, out);
+    }
 }
@@ -0,0 +1,520 @@
+//! `HashTree`, for keeping the hash of mutable content up to date.
+//!
+//! Version 1 of the serialized format is laid out like this. All integers are
+//! little-endian.
+//!
+//! | offset | length | field                                             |
+//! |--------|--------|---------------------------------------------------|
+//! | 0      | 1      | format version, currently 1                       |
+//! | 1      | 1      | mode flags: 0, `KEYED_HASH`, `DERIVE_KEY_MATERIAL` |
+//! | 2      | 32     | key words                                         |
+//! | 34     | 8      | content length                                    |
+//! | 42     | 32     | input chaining value of the final block           |
+//! | 74     | 64     | final block of the content, zero-padded           |
+//! | 138    | 32 * n | the chaining values, level by level               |
+//!
+//! The final block is the last block of the last chunk, which is needed to
+//! compute the root when the content is a single chunk. The chaining values
+//! start with one for each chunk, followed by one for each complete subtree
+//! of two chunks, then four chunks, and so on, up to the largest power of two
+//! that's no more than the number of chunks. All of them are non-root
+//! chaining values.
+
+use crate::encode::chunk_count;
+use crate::{
+    compress_chunks_parallel, compress_parents_parallel, parent_node_output, platform,
+    subtree::Mode, CVBytes, CVWords, ChunkState, Hash, Output, OutputReader, BLOCK_LEN, CHUNK_LEN,
+    CHUNK_START, DERIVE_KEY_MATERIAL, IV, KEYED_HASH, OUT_LEN,
+};
+use arrayref::{array_ref, mut_array_refs};
+use core::cmp;
+use core::fmt;
+use core::ops::Range;
+use platform::Platform;
+use std::io;
+use std::io::prelude::*;
+
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 138;
+
+/// The chaining values of every chunk and complete subtree of some content,
+/// which can be updated in place when the content changes.
+///
+/// Rehashing a large file after a small write takes time proportional to the
+/// size of the file. A `HashTree` keeps all the chaining values from the
+/// first pass, so after a write it only rehashes the chunks that changed and
+/// the parent nodes on their paths to the root.
+/// [`update_range`](HashTree::update_range) takes time proportional to the
+/// length of the range plus the logarithm of the content length, and
+/// [`resize`](HashTree::resize) handles appends and truncation the same way.
+///
+/// The tree doesn't keep the content itself. Each method takes the current
+/// contents as a slice, typically a memory map of a file, and reads only the
+/// chunks that it needs to rehash. It's the caller's responsibility to report
+/// every change. [`finalize`](HashTree::finalize) returns the same hash as
+/// [`hash`](crate::hash) (or [`keyed_hash`](crate::keyed_hash), or
+/// [`derive_key`](crate::derive_key)) of the current contents, as long as
+/// every change since the tree was built has been passed to `update_range` or
+/// `resize`.
+///
+/// A tree takes about 64 bytes of memory for every KiB of content. It can be
+/// saved with [`write_to`](HashTree::write_to) and loaded again with
+/// [`read_from`](HashTree::read_from). Note that the serialized tree includes
+/// the key, so in the keyed hashing and key derivation modes it must be kept
+/// as secret as the key.
+///
+/// This type is gated by the `std` Cargo feature, which is enabled by
+/// default.
+///
+/// # Example
+///
+/// ```
+/// let mut content = vec![0xab; 1_000_000];
+/// let mut tree = blake3::HashTree::new(&content);
+/// assert_eq!(tree.finalize(), blake3::hash(&content));
+///
+/// // Overwrite a few bytes in the middle.
+/// content[500_000..500_010].copy_from_slice(b"0123456789");
+/// tree.update_range(&content, 500_000..500_010);
+/// assert_eq!(tree.finalize(), blake3::hash(&content));
+///
+/// // Append some bytes.
+/// content.extend_from_slice(b"more content");
+/// tree.resize(&content);
+/// assert_eq!(tree.finalize(), blake3::hash(&content));
+/// ```
+#[derive(Clone)]
+pub struct HashTree {
+    key: CVWords,
+    flags: u8,
+    platform: Platform,
+    content_len: u64,
+    // levels[k] holds the CVs of the complete subtrees of 2^k chunks, packed
+    // together. The last chunk is included in level 0 even if it's partial.
+    levels: Vec<Vec<u8>>,
+    // The output of the last chunk, which is the root if there's only one.
+    last_chunk: Output,
+}
+
+impl HashTree {
+    fn new_internal(key: &CVWords, flags: u8, content: &[u8]) -> Self {
+        let platform = Platform::detect();
+        let mut tree = Self {
+            key: *key,
+            flags,
+            platform,
+            content_len: 0,
+            levels: Vec::new(),
+            last_chunk: ChunkState::new(key, 0, flags, platform).output(),
+        };
+        tree.resize(content);
+        tree
+    }
+
+    /// Build a `HashTree` of `content` for the regular hash function.
+    pub fn new(content: &[u8]) -> Self {
+        Self::new_internal(IV, 0, content)
+    }
+
+    /// Build a `HashTree` of `content` for the keyed hash function or the key
+    /// derivation function. Build the [`Mode`] once and reuse it, because
+    /// [`Mode::derive_key`] hashes the context string.
+    pub fn with_mode(mode: &Mode, content: &[u8]) -> Self {
+        Self::new_internal(&mode.key, mode.flags, content)
+    }
+
+    /// The length of the content, as of the last call to
+    /// [`resize`](HashTree::resize).
+    pub fn content_len(&self) -> u64 {
+        self.content_len
+    }
+
+    /// Rehash the chunks that overlap `range`, after the bytes in that range
+    /// were modified in place. `content` is the whole current contents.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length of `content` has changed, or if `range` extends
+    /// past the end of `content`. Use [`resize`](HashTree::resize) when the
+    /// length changes.
+    pub fn update_range(&mut self, content: &[u8], range: Range<usize>) {
+        assert_eq!(
+            content.len() as u64,
+            self.content_len,
+            "content length changed"
+        );
+        assert!(range.start <= range.end, "range start after end");
+        assert!(range.end <= content.len(), "range past the end");
+        if range.is_empty() {
+            return;
+        }
+        let first_chunk = (range.start / CHUNK_LEN) as u64;
+        let end_chunk = ((range.end - 1) / CHUNK_LEN + 1) as u64;
+        self.rehash(content, first_chunk, end_chunk);
+    }
+
+    /// Update the tree after the length of the content changed, either by
+    /// appending bytes or by truncating. `content` is the whole current
+    /// contents.
+    ///
+    /// Only the new chunks, and the old or new last chunk, are rehashed. Any
+    /// modifications to the content before that point need to be reported
+    /// separately with [`update_range`](HashTree::update_range), after
+    /// resizing.
+    pub fn resize(&mut self, content: &[u8]) {
+        let old_chunks = chunk_count(self.content_len);
+        let new_chunks = chunk_count(content.len() as u64);
+        // A new tree starts with no levels at all, so that every chunk gets
+        // hashed below.
+        let first_chunk = if self.levels.is_empty() {
+            0
+        } else {
+            cmp::min(old_chunks, new_chunks) - 1
+        };
+        let num_levels = (u64::BITS - new_chunks.leading_zeros()) as usize;
+        self.levels.resize(num_levels, Vec::new());
+        for (level, cvs) in self.levels.iter_mut().enumerate() {
+            cvs.resize((new_chunks >> level) as usize * OUT_LEN, 0);
+        }
+        self.content_len = content.len() as u64;
+        self.rehash(content, first_chunk, new_chunks);
+    }
+
+    /// Return the [`Hash`] of the content.
+    pub fn finalize(&self) -> Hash {
+        self.root_output().root_hash()
+    }
+
+    /// Return an [`OutputReader`], which can supply any number of output bytes
+    /// for the content.
+    pub fn finalize_xof(&self) -> OutputReader {
+        OutputReader::new(self.root_output())
+    }
+
+    /// Serialize the tree. See the [`HashTree`] docs for a note about keys.
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        let mut header = [0; HEADER_LEN];
+        {
+            let (version, flags, key, content_len, input_cv, block) =
+                mut_array_refs![&mut header, 1, 1, 32, 8, 32, BLOCK_LEN];
+            version[0] = VERSION;
+            flags[0] = self.flags;
+            *key = platform::le_bytes_from_words_32(&self.key);
+            *content_len = self.content_len.to_le_bytes();
+            *input_cv = platform::le_bytes_from_words_32(&self.last_chunk.input_chaining_value);
+            *block = self.last_chunk.block;
+        }
+        writer.write_all(&header)?;
+        for cvs in &self.levels {
+            writer.write_all(cvs)?;
+        }
+        Ok(())
+    }
+
+    /// Deserialize a tree written by [`write_to`](HashTree::write_to).
+    ///
+    /// This returns an error of kind
+    /// [`InvalidData`](io::ErrorKind::InvalidData) if the header is invalid,
+    /// or if the chaining value of the last chunk doesn't match its final
+    /// block. The other chaining values aren't checked.
+    pub fn read_from(mut reader: impl Read) -> io::Result<Self> {
+        fn invalid(reason: &'static str) -> io::Error {
+            io::Error::new(io::ErrorKind::InvalidData, reason)
+        }
+
+        let mut header = [0; HEADER_LEN];
+        reader.read_exact(&mut header)?;
+        if header[0] != VERSION {
+            return Err(invalid("unsupported hash tree version"));
+        }
+        let flags = header[1];
+        let key = platform::words_from_le_bytes_32(array_ref!(header, 2, 32));
+        let content_len = u64::from_le_bytes(*array_ref!(header, 34, 8));
+        let input_cv = platform::words_from_le_bytes_32(array_ref!(header, 42, 32));
+        let block = *array_ref!(header, 74, BLOCK_LEN);
+        if flags != 0 && flags != KEYED_HASH && flags != DERIVE_KEY_MATERIAL {
+            return Err(invalid("invalid hash tree flags"));
+        }
+        if flags == 0 && key != *IV {
+            return Err(invalid("wrong key for the regular hash mode"));
+        }
+        let num_chunks = chunk_count(content_len);
+        if num_chunks > usize::MAX as u64 / (2 * OUT_LEN) as u64 {
+            return Err(invalid("hash tree content length out of range"));
+        }
+
+        let platform = Platform::detect();
+        let last_chunk = last_chunk_output(&key, flags, platform, content_len, input_cv, block);
+        if block[last_chunk.block_len as usize..]
+            .iter()
+            .any(|&b| b != 0)
+        {
+            return Err(invalid("nonzero bytes past the end of the final block"));
+        }
+        if last_chunk.flags & CHUNK_START != 0 && input_cv != key {
+            return Err(invalid("final block input CV doesn't match the key"));
+        }
+
+        let num_levels = (u64::BITS - num_chunks.leading_zeros()) as usize;
+        let mut levels = Vec::with_capacity(num_levels);
+        for level in 0..num_levels {
+            // Don't trust the length enough to allocate it all up front.
+            let level_len = (num_chunks >> level) * OUT_LEN as u64;
+            let mut cvs = Vec::new();
+            (&mut reader).take(level_len).read_to_end(&mut cvs)?;
+            if cvs.len() as u64 != level_len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            levels.push(cvs);
+        }
+        let last_cv = array_ref!(levels[0], (num_chunks as usize - 1) * OUT_LEN, OUT_LEN);
+        if last_chunk.chaining_value() != *last_cv {
+            return Err(invalid("last chunk CV doesn't match the final block"));
+        }
+        Ok(Self {
+            key,
+            flags,
+            platform,
+            content_len,
+            levels,
+            last_chunk,
+        })
+    }
+
+    // Rehash the chunks in first_chunk..end_chunk and all their ancestors. The
+    // levels must already be the right size for the content.
+    fn rehash(&mut self, content: &[u8], first_chunk: u64, end_chunk: u64) {
+        let num_chunks = chunk_count(content.len() as u64);
+        debug_assert!(first_chunk < end_chunk && end_chunk <= num_chunks);
+
+        // Hash whole batches of chunks with SIMD, except the last chunk,
+        // which might be the root and gets hashed separately below.
+        let batch_len = self.platform.simd_degree() * CHUNK_LEN;
+        let start = first_chunk as usize * CHUNK_LEN;
+        let end = cmp::min(end_chunk, num_chunks - 1) as usize * CHUNK_LEN;
+        if start < end {
+            let out = &mut self.levels[0][first_chunk as usize * OUT_LEN..];
+            for (i, batch) in content[start..end].chunks(batch_len).enumerate() {
+                let counter = first_chunk + (i * batch_len / CHUNK_LEN) as u64;
+                let out = &mut out[i * batch_len / CHUNK_LEN * OUT_LEN..];
+                compress_chunks_parallel(batch, &self.key, counter, self.flags, self.platform, out);
+            }
+        }
+        if end_chunk == num_chunks {
+            let last_start = (num_chunks - 1) as usize * CHUNK_LEN;
+            let mut chunk_state =
+                ChunkState::new(&self.key, num_chunks - 1, self.flags, self.platform);
+            chunk_state.update(&content[last_start..]);
+            self.last_chunk = chunk_state.output();
+            self.levels[0][last_start / CHUNK_LEN * OUT_LEN..]
+                .copy_from_slice(&self.last_chunk.chaining_value());
+        }
+
+        // Rehash the parents of everything that changed, one level at a time.
+        let batch_children = 2 * self.platform.simd_degree();
+        for level in 1..self.levels.len() {
+            let first = (first_chunk >> level) as usize;
+            let end = cmp::min(((end_chunk - 1) >> level) + 1, num_chunks >> level) as usize;
+            if first >= end {
+                break;
+            }
+            let (below, above) = self.levels.split_at_mut(level);
+            let children = &below[level - 1][2 * first * OUT_LEN..2 * end * OUT_LEN];
+            let out = &mut above[0][first * OUT_LEN..];
+            for (i, batch) in children.chunks(batch_children * OUT_LEN).enumerate() {
+                let out = &mut out[i * batch_children / 2 * OUT_LEN..];
+                compress_parents_parallel(batch, &self.key, self.flags, self.platform, out);
+            }
+        }
+    }
+
+    // The CV of the complete subtree of 2^level chunks at the given index.
+    pub(crate) fn cv(&self, level: usize, index: u64) -> &CVBytes {
+        array_ref!(self.levels[level], index as usize * OUT_LEN, OUT_LEN)
+    }
+
+    fn root_output(&self) -> Output {
+        let num_chunks = chunk_count(self.content_len);
+        if num_chunks == 1 {
+            return self.last_chunk.clone();
+        }
+        // The root's children are the largest complete subtree on the left,
+        // and the rest of the tree on the right, which is itself made of
+        // complete subtrees with decreasing sizes. Those are the 1 bits of
+        // num_chunks, except that if num_chunks is a power of two, its single
+        // complete subtree is the root, and we need its children instead.
+        let mut subtrees = Vec::new();
+        let mut chunks_so_far = 0;
+        for level in (0..self.levels.len()).rev() {
+            if num_chunks & (1 << level) != 0 {
+                subtrees.push(self.cv(level, chunks_so_far >> level));
+                chunks_so_far += 1 << level;
+            }
+        }
+        if subtrees.len() == 1 {
+            let level = self.levels.len() - 2;
+            subtrees = vec![self.cv(level, 0), self.cv(level, 1)];
+        }
+        let mut right = *subtrees.pop().unwrap();
+        while subtrees.len() > 1 {
+            let left = subtrees.pop().unwrap();
+            right = parent_node_output(left, &right, &self.key, self.flags, self.platform)
+                .chaining_value();
+        }
+        parent_node_output(subtrees[0], &right, &self.key, self.flags, self.platform)
+    }
+}
+
+// Reconstruct the output of the last chunk from its final block.
+fn last_chunk_output(
+    key: &CVWords,
+    flags: u8,
+    platform: Platform,
+    content_len: u64,
+    input_cv: CVWords,
+    block: [u8; BLOCK_LEN],
+) -> Output {
+    let last_chunk_len = content_len - (chunk_count(content_len) - 1) * CHUNK_LEN as u64;
+    let blocks_before = if last_chunk_len == 0 {
+        0
+    } else {
+        (last_chunk_len - 1) / BLOCK_LEN as u64
+    };
+    let mut chunk_state = ChunkState::new(key, chunk_count(content_len) - 1, flags, platform);
+    chunk_state.cv = input_cv;
+    chunk_state.blocks_compressed = blocks_before as u8;
+    chunk_state.buf = block;
+    chunk_state.buf_len = (last_chunk_len - blocks_before * BLOCK_LEN as u64) as u8;
+    chunk_state.output()
+}
+
+// Don't derive(Debug), because the key may be secret.
+impl fmt::Debug for HashTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HashTree")
+            .field("flags", &self.flags)
+            .field("content_len", &self.content_len)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{paint_test_input, TEST_CASES, TEST_KEY};
+    use rand::prelude::*;
+
+    const CONTEXT: &str = "HashTree test context";
+
+    fn trees(content: &[u8]) -> [(HashTree, Hash); 3] {
+        [
+            (HashTree::new(content), crate::hash(content)),
+            (
+                HashTree::with_mode(&Mode::keyed_hash(&TEST_KEY), content),
+                crate::keyed_hash(&TEST_KEY, content),
+            ),
+            (
+                HashTree::with_mode(&Mode::derive_key(CONTEXT), content),
+                Hash(crate::derive_key(CONTEXT, content)),
+            ),
+        ]
+    }
+
+    fn expected_hash(tree: &HashTree, content: &[u8]) -> Hash {
+        match tree.flags {
+            0 => crate::hash(content),
+            KEYED_HASH => crate::keyed_hash(&TEST_KEY, content),
+            _ => Hash(crate::derive_key(CONTEXT, content)),
+        }
+    }
+
+    #[test]
+    fn test_new() {
+        for &case in TEST_CASES {
+            dbg!(case);
+            let mut content = vec![0; case];
+            paint_test_input(&mut content);
+            for (tree, expected) in &trees(&content) {
+                assert_eq!(tree.content_len(), case as u64);
+                assert_eq!(tree.finalize(), *expected);
+                let mut xof = [0; 100];
+                tree.finalize_xof().fill(&mut xof);
+                assert_eq!(xof[..32], *expected.as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn test_random_edits() {
+        let mut rng = rand_chacha::ChaCha8Rng::from_seed([3; 32]);
+        let mut content = vec![0; 20 * CHUNK_LEN + 7];
+        paint_test_input(&mut content);
+        for (mut tree, _) in trees(&content) {
+            let mut content = content.clone();
+            for _ in 0..100 {
+                match rng.gen_range(0..3) {
+                    0 if !content.is_empty() => {
+                        let start = rng.gen_range(0..content.len());
+                        let end = rng.gen_range(start..=content.len());
+                        rng.fill(&mut content[start..end]);
+                        tree.update_range(&content, start..end);
+                    }
+                    1 => {
+                        let new_len = rng.gen_range(0..content.len() + 1);
+                        content.truncate(new_len);
+                        tree.resize(&content);
+                    }
+                    _ => {
+                        let extra = rng.gen_range(0..10 * CHUNK_LEN);
+                        content.extend((0..extra).map(|_| rng.gen::<u8>()));
+                        tree.resize(&content);
+                    }
+                }
+                assert_eq!(tree.finalize(), expected_hash(&tree, &content));
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        for &case in &[
+            0,
+            1,
+            CHUNK_LEN,
+            CHUNK_LEN + 1,
+            5 * CHUNK_LEN,
+            31 * CHUNK_LEN - 1,
+        ] {
+            dbg!(case);
+            let mut content = vec![0; case];
+            paint_test_input(&mut content);
+            for (tree, expected) in &trees(&content) {
+                let mut bytes = Vec::new();
+                tree.write_to(&mut bytes).unwrap();
+                let num_chunks = chunk_count(case as u64);
+                let num_cvs: u64 = (0..64).map(|level| num_chunks >> level).sum();
+                assert_eq!(
+                    bytes.len() as u64,
+                    HEADER_LEN as u64 + num_cvs * OUT_LEN as u64
+                );
+                let mut loaded = HashTree::read_from(&bytes[..]).unwrap();
+                assert_eq!(loaded.finalize(), *expected);
+
+                // The loaded tree can still be updated.
+                let mut content = content.clone();
+                content.push(42);
+                loaded.resize(&content);
+                assert_eq!(loaded.finalize(), expected_hash(tree, &content));
+
+                // Truncated input is an error.
+                HashTree::read_from(&bytes[..bytes.len() - 1]).unwrap_err();
+
+                // So is a final block that doesn't match the last chunk CV.
+                let mut corrupt = bytes.clone();
+                corrupt[42] ^= 1;
+                let err = HashTree::read_from(&corrupt[..]).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            }
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Helper functions for efficient IO.
+
+// A 16 KiB buffer is enough to take advantage of all the SIMD instruction sets
+// that we support, but `std::io::copy` currently uses 8 KiB. Most platforms
+// can support at least 64 KiB, and there's some performance benefit to using
+// bigger reads, so that's what we use here.
+#[cfg(feature = "std")]
+pub(crate) fn copy_wide(
+    mut reader: impl std::io::Read,
+    hasher: &mut crate::Hasher,
+) -> std::io::Result<u64> {
+    let mut buffer = [0; 65536];
+    let mut total = 0;
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => return Ok(total),
+            Ok(n) => {
+                hasher.update(&buffer[..n]);
+                total += n as u64;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// The size of each of the two buffers that copy_wide_rayon() alternates
+// between. Hashing a buffer with update_rayon() requires all the worker
+// threads to synchronize at the end, so the buffer needs to be large enough
+// for that overhead to be small. The rule of thumb in the update_rayon() docs
+// says that multithreading doesn't pay off below 128 KiB, and this is
+// comfortably above that.
+#[cfg(feature = "rayon")]
+const RAYON_BUFFER_LEN: usize = 1 << 20; // 1 MiB
+
+// Read from `reader` until `buf` is full or we reach EOF, and return the
+// number of bytes read. Unlike Read::read_exact, hitting EOF isn't an error.
+// If there's an error, also return the number of bytes read before it.
+#[cfg(feature = "rayon")]
+fn read_full(mut reader: impl std::io::Read, buf: &mut [u8]) -> (usize, std::io::Result<()>) {
+    let mut total = 0;
+    while total < buf.len() {
+        match reader.read(&mut buf[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return (total, Err(e)),
+        }
+    }
+    (total, Ok(()))
+}
+
+// A multithreaded version of copy_wide(), using double buffering. While the
+// Rayon pool hashes one buffer with update_rayon(), we read into the other
+// buffer at the same time, and then the two buffers trade places. Without
+// double buffering, all the worker threads would have to sit idle every time
+// the buffer was refilled, and that overhead would cancel out most of the
+// benefit of multithreading.
+//
+// Each buffer is always completely filled before it's hashed (except at EOF),
+// even if the reader returns short reads. That keeps the length of each
+// update() a large power of two, which lets compress_subtree_wide() use all
+// its parallelism.
+#[cfg(feature = "rayon")]
+pub(crate) fn copy_wide_rayon(
+    mut reader: impl std::io::Read + Send,
+    hasher: &mut crate::Hasher,
+) -> std::io::Result<u64> {
+    let mut hashing_buf = vec![0; RAYON_BUFFER_LEN];
+    let mut reading_buf = vec![0; RAYON_BUFFER_LEN];
+    let (mut hashing_len, mut result) = read_full(&mut reader, &mut hashing_buf);
+    let mut total = 0;
+    while result.is_ok() && hashing_len > 0 {
+        let (_, (reading_len, reading_result)) = rayon::join(
+            || hasher.update_rayon(&hashing_buf[..hashing_len]),
+            || read_full(&mut reader, &mut reading_buf),
+        );
+        total += hashing_len as u64;
+        hashing_len = reading_len;
+        result = reading_result;
+        core::mem::swap(&mut hashing_buf, &mut reading_buf);
+    }
+    // If the last read failed partway through, hash the bytes it got before
+    // the error, to match the behavior of copy_wide(). In the EOF case, this
+    // is empty.
+    hasher.update_rayon(&hashing_buf[..hashing_len]);
+    total += hashing_len as u64;
+    result.map(|()| total)
+}
+
+// Mmap a file, if it looks like a good idea. Return None in cases where we
+// know mmap will fail, or if the file is short enough that mmapping isn't
+// worth it. However, if we do try to mmap and it fails, return the error.
+//
+// SAFETY: Mmaps are fundamentally unsafe, because another process can modify
+// the file while we're reading it. Handing a safe caller a `&[u8]` that's
+// backed by an mmap would be unsound, because they could e.g. validate it with
+// `str::from_utf8` and then watch it change. This function is crate-private,
+// and its only callers pass the mapping straight into the hasher. The worst
+// that can happen in the event of a race is that we hash garbage bytes or
+// crash with SIGBUS, neither of which risks memory corruption in the caller.
+#[cfg(feature = "mmap")]
+pub(crate) fn maybe_mmap_file(file: &std::fs::File) -> std::io::Result<Option<memmap2::Mmap>> {
+    let metadata = file.metadata()?;
+    let file_size = metadata.len();
+    Ok(if !metadata.is_file() {
+        // Not a real file.
+        None
+    } else if file_size > isize::MAX as u64 {
+        // Too long to safely map.
+        // https://github.com/danburkert/memmap-rs/issues/69
+        None
+    } else if file_size == 0 {
+        // Mapping an empty file currently fails.
+        // https://github.com/danburkert/memmap-rs/issues/72
+        None
+    } else if file_size < 16 * 1024 {
+        // Mapping small files is not worth it.
+        None
+    } else {
+        // Explicitly set the length of the memory map, so that filesystem
+        // changes can't race to violate the invariants we just checked.
+        let map = unsafe {
+            memmap2::MmapOptions::new()
+                .len(file_size as usize)
+                .map(file)?
+        };
+        Some(map)
+    })
+}
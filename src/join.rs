@@ -1,25 +1,66 @@
-//! The multi-threading abstractions used by `Hasher::update_with_join`.
+//! The multi-threading abstractions used by [`Hasher::update_with_join`].
 //!
-//! Different implementations of the `Join` trait determine whether
-//! `Hasher::update_with_join` performs multi-threading on sufficiently large
-//! inputs. The `SerialJoin` implementation is single-threaded, and the
-//! `RayonJoin` implementation (gated by the `rayon` feature) is multi-threaded.
-//! Interfaces other than `Hasher::update_with_join`, like [`hash`](crate::hash)
-//! and [`Hasher::update`](crate::Hasher::update), always use `SerialJoin`
-//! internally.
+//! Different implementations of the [`Join`] trait determine whether
+//! [`Hasher::update_with_join`] performs multi-threading on sufficiently large
+//! inputs. The [`SerialJoin`] implementation is single-threaded, the
+//! [`ThreadJoin`] implementation (gated by the `std` feature) uses scoped
+//! standard library threads, and the [`RayonJoin`] implementation (gated by
+//! the `rayon` feature) uses the Rayon thread pool. Interfaces other than
+//! `Hasher::update_with_join`, like [`hash`](crate::hash) and
+//! [`Hasher::update`], always use `SerialJoin` internally, and
+//! [`Hasher::update_rayon`] uses `RayonJoin`.
 //!
-//! The `Join` trait is an almost exact copy of the [`rayon::join`] API, and
-//! `RayonJoin` is the only non-trivial implementation. Previously this trait
-//! was public, but currently it's been re-privatized, as it's both 1) of no
-//! value to most callers and 2) a pretty big implementation detail to commit
-//! to.
+//! The `Join` trait is an almost exact copy of the [`rayon::join`] API. Callers
+//! with their own executor can implement it to run BLAKE3's tree hashing on
+//! that executor. An implementation must run both closures to completion and
+//! return both results, in order, but it's free to run them on any threads,
+//! or one after the other. The hash doesn't depend on the implementation.
 //!
+//! # Example
+//!
+//! ```
+//! # #[cfg(feature = "std")] {
+//! use blake3::join::{Join, SerialJoin, ThreadJoin};
+//!
+//! // A Join implementation that delegates to another one.
+//! enum MyJoin {}
+//!
+//! impl Join for MyJoin {
+//!     fn join<A, B, RA, RB>(oper_a: A, oper_b: B) -> (RA, RB)
+//!     where
+//!         A: FnOnce() -> RA + Send,
+//!         B: FnOnce() -> RB + Send,
+//!         RA: Send,
+//!         RB: Send,
+//!     {
+//!         SerialJoin::join(oper_a, oper_b)
+//!     }
+//! }
+//!
+//! let input = vec![0xab; 1 << 20];
+//! let expected = blake3::hash(&input);
+//! let mut hasher = blake3::Hasher::new();
+//! hasher.update_with_join::<MyJoin>(&input);
+//! assert_eq!(hasher.finalize(), expected);
+//!
+//! let mut hasher = blake3::Hasher::new();
+//! hasher.update_with_join::<ThreadJoin>(&input);
+//! assert_eq!(hasher.finalize(), expected);
+//! # }
+//! ```
+//!
+//! [`Hasher::update_with_join`]: crate::Hasher::update_with_join
+//! [`Hasher::update`]: crate::Hasher::update
+//! [`Hasher::update_rayon`]: ../struct.Hasher.html#method.update_rayon
+//! [`RayonJoin`]: enum.RayonJoin.html
 //! [`rayon::join`]: https://docs.rs/rayon/1.3.0/rayon/fn.join.html
 
 /// The trait that abstracts over single-threaded and multi-threaded recursion.
 ///
 /// See the [`join` module docs](index.html) for more details.
 pub trait Join {
+    /// Run `oper_a` and `oper_b`, potentially in parallel, and return both of
+    /// their results.
     fn join<A, B, RA, RB>(oper_a: A, oper_b: B) -> (RA, RB)
     where
         A: FnOnce() -> RA + Send,
@@ -49,11 +90,107 @@ impl Join for SerialJoin {
     }
 }
 
+/// The scoped-thread implementation of `Join`. The right side is executed on
+/// a new thread from [`std::thread::scope`], while the left side runs on the
+/// calling thread. This needs no dependencies beyond the standard library, and
+/// it's gated by the `std` feature, which is on by default.
+///
+/// To avoid spawning a thread for every split in the tree, `ThreadJoin` only
+/// spawns threads in the top levels of recursion, enough to give each
+/// available CPU (according to [`std::thread::available_parallelism`]) one
+/// thread. Deeper levels run serially. Spawning threads is relatively
+/// expensive, so this is only worth using for large inputs, and a thread pool
+/// like [`RayonJoin`] is usually faster when it's available.
+///
+/// If either side panics, the panic is propagated to the caller after both
+/// sides have finished.
+///
+/// See the [`join` module docs](index.html) for more details.
+#[cfg(feature = "std")]
+pub enum ThreadJoin {}
+
+#[cfg(feature = "std")]
+std::thread_local! {
+    // The current depth of ThreadJoin recursion on this thread, and the depth
+    // below which it stops spawning threads.
+    static THREAD_JOIN_DEPTH: core::cell::Cell<(u32, u32)> =
+        const { core::cell::Cell::new((0, 0)) };
+}
+
+// Restores the recursion depth of the current thread, even if the left side
+// panics.
+#[cfg(feature = "std")]
+struct ThreadJoinDepthGuard((u32, u32));
+
+#[cfg(feature = "std")]
+impl Drop for ThreadJoinDepthGuard {
+    fn drop(&mut self) {
+        THREAD_JOIN_DEPTH.with(|depth| depth.set(self.0));
+    }
+}
+
+#[cfg(feature = "std")]
+impl Join for ThreadJoin {
+    fn join<A, B, RA, RB>(oper_a: A, oper_b: B) -> (RA, RB)
+    where
+        A: FnOnce() -> RA + Send,
+        B: FnOnce() -> RB + Send,
+        RA: Send,
+        RB: Send,
+    {
+        let (depth, mut max_depth) = THREAD_JOIN_DEPTH.with(|depth| depth.get());
+        if depth == 0 {
+            let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+            // The number of splits needed to get one thread per CPU, rounded up.
+            max_depth = usize::BITS - (threads - 1).leading_zeros();
+        }
+        if depth >= max_depth {
+            return (oper_a(), oper_b());
+        }
+        let child_depth = (depth + 1, max_depth);
+        std::thread::scope(|scope| {
+            let handle = scope.spawn(move || {
+                THREAD_JOIN_DEPTH.with(|depth| depth.set(child_depth));
+                oper_b()
+            });
+            let result_a = {
+                let _guard = ThreadJoinDepthGuard((depth, max_depth));
+                THREAD_JOIN_DEPTH.with(|depth| depth.set(child_depth));
+                oper_a()
+            };
+            let result_b = match handle.join() {
+                Ok(result_b) => result_b,
+                Err(panic) => std::panic::resume_unwind(panic),
+            };
+            (result_a, result_b)
+        })
+    }
+}
+
 /// The Rayon-based implementation of `Join`. The left and right sides are
 /// executed on the Rayon thread pool, potentially in parallel. This
 /// implementation is gated by the `rayon` feature, which is off by default.
 ///
+/// Like [`rayon::join`], this uses the global thread pool by default. To use
+/// a different pool, call [`Hasher::update_rayon`] or
+/// [`Hasher::update_with_join`] from inside [`ThreadPool::install`]:
+///
+/// ```
+/// let pool = rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+/// let input = vec![0xab; 1 << 20];
+/// let mut hasher = blake3::Hasher::new();
+/// pool.install(|| {
+///     hasher.update_with_join::<blake3::join::RayonJoin>(&input);
+/// });
+/// assert_eq!(hasher.finalize(), blake3::hash(&input));
+/// ```
+///
 /// See the [`join` module docs](index.html) for more details.
+///
+/// [`rayon::join`]: https://docs.rs/rayon/1.3.0/rayon/fn.join.html
+/// [`Hasher::update_rayon`]: crate::Hasher::update_rayon
+/// [`Hasher::update_with_join`]: crate::Hasher::update_with_join
+/// [`ThreadPool::install`]: https://docs.rs/rayon/1/rayon/struct.ThreadPool.html#method.install
 #[cfg(feature = "rayon")]
 pub enum RayonJoin {}
 
@@ -82,6 +219,33 @@ mod test {
         assert_eq!((2, 4), SerialJoin::join(oper_a, oper_b));
     }
 
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_thread_join() {
+        let oper_a = || 1 + 1;
+        let oper_b = || 2 + 2;
+        assert_eq!((2, 4), ThreadJoin::join(oper_a, oper_b));
+
+        // Deep recursion doesn't spawn a thread for every call, and the depth
+        // is restored afterwards.
+        fn recurse(depth: u32) -> u64 {
+            if depth == 0 {
+                return 1;
+            }
+            let (a, b) = ThreadJoin::join(|| recurse(depth - 1), || recurse(depth - 1));
+            a + b
+        }
+        assert_eq!(recurse(16), 1 << 16);
+        assert_eq!(THREAD_JOIN_DEPTH.with(|depth| depth.get().0), 0);
+
+        // Panics propagate, and they also restore the depth.
+        let result = std::panic::catch_unwind(|| {
+            ThreadJoin::join(|| recurse(4), || -> u64 { panic!("oops") })
+        });
+        assert!(result.is_err());
+        assert_eq!(THREAD_JOIN_DEPTH.with(|depth| depth.get().0), 0);
+    }
+
     #[test]
     #[cfg(feature = "rayon")]
     fn test_rayon_join() {
@@ -89,4 +253,17 @@ mod test {
         let oper_b = || 2 + 2;
         assert_eq!((2, 4), RayonJoin::join(oper_a, oper_b));
     }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn test_rayon_join_custom_pool() {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        // Both sides run on the custom pool, which has two threads.
+        let (a, b) = pool
+            .install(|| RayonJoin::join(rayon::current_num_threads, rayon::current_num_threads));
+        assert_eq!((a, b), (2, 2));
+    }
 }
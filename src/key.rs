@@ -0,0 +1,132 @@
+//! `Key`, a secret key for the keyed hash function.
+
+use crate::{Hash, HexError, KEY_LEN};
+use arrayvec::ArrayString;
+use core::fmt;
+
+/// A secret 32-byte key for [`keyed_hash`](crate::keyed_hash) and
+/// [`Hasher::new_keyed`](crate::Hasher::new_keyed).
+///
+/// Those functions take raw `&[u8; KEY_LEN]` arrays, and
+/// [`keyed_hash_with`](crate::keyed_hash_with) and
+/// [`Hasher::new_keyed_with`](crate::Hasher::new_keyed_with) take a `Key`
+/// instead, which is harder to misuse:
+///
+/// - When the `zeroize` Cargo feature is enabled, a `Key` overwrites its bytes
+///   with zeros when it's dropped. (It isn't `Copy`, so that it can implement
+///   `Drop`. Copies made with [`as_bytes`](Key::as_bytes) aren't zeroized.)
+/// - Its `Debug` representation doesn't include the key.
+/// - Its `PartialEq` implementation is constant-time.
+///
+/// A `Key` can be decoded from hex with [`from_hex`](Key::from_hex) or
+/// `FromStr`, generated by the operating system's random number generator with
+/// [`generate`](Key::generate), or derived from other key material with
+/// [`derive`](Key::derive).
+///
+/// # Example
+///
+/// ```
+/// let key = blake3::Key::derive("example.com 2019-12-25 16:18:03 MAC key v1", b"secret");
+/// let mac = blake3::keyed_hash_with(&key, b"message");
+/// assert_eq!(mac, blake3::Hasher::new_keyed_with(&key).update(b"message").finalize());
+/// ```
+#[derive(Clone)]
+pub struct Key([u8; KEY_LEN]);
+
+impl Key {
+    /// The raw bytes of the `Key`. Note that byte arrays don't provide
+    /// constant-time equality checking or zeroize on drop.
+    #[inline]
+    pub const fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+
+    /// Create a `Key` from its raw bytes.
+    pub const fn from_bytes(bytes: [u8; KEY_LEN]) -> Self {
+        Self(bytes)
+    }
+
+    /// Encode a `Key` in lowercase hexadecimal. See
+    /// [`Hash::to_hex`](crate::Hash::to_hex).
+    pub fn to_hex(&self) -> ArrayString<{ 2 * KEY_LEN }> {
+        Hash::from_bytes(self.0).to_hex()
+    }
+
+    /// Decode a `Key` from hexadecimal. See
+    /// [`Hash::from_hex`](crate::Hash::from_hex).
+    pub fn from_hex(hex: impl AsRef<[u8]>) -> Result<Self, HexError> {
+        Hash::from_hex(hex).map(|hash| Self(hash.0))
+    }
+
+    /// Generate a random `Key` with the operating system's random number
+    /// generator.
+    ///
+    /// This method is gated by the `getrandom` Cargo feature, which is
+    /// disabled by default.
+    #[cfg(feature = "getrandom")]
+    pub fn generate() -> std::io::Result<Self> {
+        let mut key = Self([0; KEY_LEN]);
+        getrandom::getrandom(&mut key.0)?;
+        Ok(key)
+    }
+
+    /// Derive a `Key` from key material with
+    /// [`derive_key`](crate::derive_key). The context string should be
+    /// hardcoded, globally unique, and application-specific.
+    pub fn derive(context: &str, key_material: &[u8]) -> Self {
+        // Derive directly into the Key, so that there's no temporary copy to
+        // zeroize.
+        let mut key = Self([0; KEY_LEN]);
+        crate::derive_key_into(context, key_material, &mut key.0);
+        key
+    }
+}
+
+impl From<[u8; KEY_LEN]> for Key {
+    #[inline]
+    fn from(bytes: [u8; KEY_LEN]) -> Self {
+        Self::from_bytes(bytes)
+    }
+}
+
+impl core::str::FromStr for Key {
+    type Err = HexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Key::from_hex(s)
+    }
+}
+
+/// This implementation is constant-time.
+impl PartialEq for Key {
+    #[inline]
+    fn eq(&self, other: &Key) -> bool {
+        constant_time_eq::constant_time_eq_32(&self.0, &other.0)
+    }
+}
+
+impl Eq for Key {}
+
+// Don't derive(Debug), because the key is secret.
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Key").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl zeroize::Zeroize for Key {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for Key {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(self);
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl zeroize::ZeroizeOnDrop for Key {}
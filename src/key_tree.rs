@@ -0,0 +1,161 @@
+//! Hierarchical key derivation, for key hierarchies like tenant → service →
+//! purpose → rotation epoch.
+
+use crate::{DeriveKeyContext, Key, KEY_LEN};
+use core::fmt;
+
+/// The context string used to derive every child key in a [`KeyTree`].
+pub const KEY_TREE_CONTEXT: &str = "BLAKE3 2026-10-17 12:00:00 KeyTree child key v1";
+
+static CONTEXT: DeriveKeyContext = DeriveKeyContext::new(KEY_TREE_CONTEXT);
+
+/// A node in a tree of derived keys.
+///
+/// Each node of a `KeyTree` holds a 32-byte key, and each child is derived
+/// from its parent's key and a label with [`derive_key`](crate::derive_key),
+/// using the context string [`KEY_TREE_CONTEXT`] and this key material:
+///
+/// | length | field                                   |
+/// |--------|-----------------------------------------|
+/// | 32     | the parent key                          |
+/// | 8      | the length of the label, little-endian  |
+/// | n      | the label                               |
+///
+/// A path of several labels is derived one label at a time, so the key at
+/// `["a", "b"]` is the child `"b"` of the child `"a"` of the root. The length
+/// prefix keeps the encoding unambiguous even though labels are arbitrary
+/// bytes.
+///
+/// Every derivation needs the parent key, which in BIP32 terms means that all
+/// derivation here is "hardened". Symmetric keys don't have public halves, so
+/// there's no unhardened variant. A child key reveals nothing about its parent
+/// or its siblings, so an intermediate key can be exported with
+/// [`key`](KeyTree::key) and handed to a service that only needs its own subtree.
+///
+/// When the `zeroize` Cargo feature is enabled, a `KeyTree` overwrites its key
+/// with zeros when it's dropped, like [`Key`].
+///
+/// # Example
+///
+/// ```
+/// let root_key = blake3::derive_key("example.com 2019-12-25 16:18:03 key tree root", b"secret");
+/// let root = blake3::KeyTree::new(&root_key);
+/// let service = root.child(b"tenant 7").child(b"billing");
+///
+/// // The billing service can be handed its own key, and it derives the same
+/// // descendants as the root does.
+/// let exported = blake3::KeyTree::new(service.key().as_bytes());
+/// assert_eq!(
+///     exported.child(b"epoch 3").key(),
+///     service.child(b"epoch 3").key(),
+/// );
+///
+/// # #[cfg(feature = "std")] {
+/// let path: blake3::DerivationPath = ["tenant 7", "billing", "epoch 3"].iter().collect();
+/// assert_eq!(root.derive(&path).key(), service.child(b"epoch 3").key());
+/// # }
+/// ```
+#[cfg_attr(feature = "zeroize", derive(zeroize::Zeroize, zeroize::ZeroizeOnDrop))]
+#[derive(Clone)]
+pub struct KeyTree {
+    key: [u8; KEY_LEN],
+}
+
+impl KeyTree {
+    /// Start a tree from a root key. The root key should be uniformly random,
+    /// or the output of [`derive_key`](crate::derive_key).
+    pub fn new(root_key: &[u8; KEY_LEN]) -> Self {
+        Self { key: *root_key }
+    }
+
+    /// Derive the child of this node with the given label.
+    pub fn child(&self, label: &[u8]) -> Self {
+        let mut hasher = CONTEXT.hasher();
+        hasher.update(&self.key);
+        hasher.update(&(label.len() as u64).to_le_bytes());
+        hasher.update(label);
+        let child = Self {
+            key: *hasher.finalize().as_bytes(),
+        };
+        // The hasher's buffer holds a copy of this node's key.
+        #[cfg(feature = "zeroize")]
+        zeroize::Zeroize::zeroize(&mut hasher);
+        child
+    }
+
+    /// Derive the descendant of this node at the end of `path`. An empty path
+    /// gives a copy of this node.
+    #[cfg(feature = "std")]
+    pub fn derive(&self, path: &DerivationPath) -> Self {
+        let mut node = self.clone();
+        for label in path.labels() {
+            node = node.child(label);
+        }
+        node
+    }
+
+    /// Export the key at this node. Anyone with this key can derive every
+    /// descendant of this node, but none of its ancestors or siblings.
+    pub fn key(&self) -> Key {
+        Key::from_bytes(self.key)
+    }
+}
+
+// Don't derive(Debug), because the key is secret.
+impl fmt::Debug for KeyTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KeyTree").finish_non_exhaustive()
+    }
+}
+
+/// An ordered list of labels, naming a node in a [`KeyTree`] relative to some
+/// ancestor.
+///
+/// Labels are arbitrary bytes. A `DerivationPath` can be built with
+/// [`push`](DerivationPath::push), or collected from an iterator of strings or
+/// byte slices.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct DerivationPath {
+    labels: Vec<Vec<u8>>,
+}
+
+#[cfg(feature = "std")]
+impl DerivationPath {
+    /// Construct an empty path.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a label to the end of the path.
+    pub fn push(&mut self, label: impl AsRef<[u8]>) -> &mut Self {
+        self.labels.push(label.as_ref().to_vec());
+        self
+    }
+
+    /// The labels of the path, from the first to the last.
+    pub fn labels(&self) -> impl Iterator<Item = &[u8]> {
+        self.labels.iter().map(|label| &label[..])
+    }
+
+    /// The number of labels in the path.
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    /// Whether the path has no labels.
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<L: AsRef<[u8]>> FromIterator<L> for DerivationPath {
+    fn from_iter<I: IntoIterator<Item = L>>(iter: I) -> Self {
+        let mut path = Self::new();
+        for label in iter {
+            path.push(label);
+        }
+        path
+    }
+}
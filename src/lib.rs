@@ -40,8 +40,28 @@
 //! binary will not be portable to other machines.
 //!
 //! The `rayon` feature (disabled by default, but enabled for [docs.rs]) adds
-//! the [`Hasher::update_rayon`] method, for multithreaded hashing. However,
-//! even if this feature is enabled, all other APIs remain single-threaded.
+//! the [`Hasher::update_rayon`], [`Hasher::update_rayon_with`], and
+//! [`Hasher::update_reader_rayon`] methods, for multithreaded hashing, the
+//! [`hash_many_rayon`] family of functions, for multithreaded batch hashing,
+//! and the [`OutputReader::fill_rayon`] method, for multithreaded extended
+//! output. However, even if this feature is enabled, all other APIs remain
+//! single-threaded. This feature implies `std`.
+//!
+//! The `mmap` feature (disabled by default, but enabled for [docs.rs]) adds the
+//! [`Hasher::update_mmap`] method, and together with `rayon` also the
+//! [`Hasher::update_mmap_rayon`] method, for hashing files with memory
+//! mapping. This feature implies `std`.
+//!
+//! The `serde` feature (disabled by default) implements `Serialize` and
+//! `Deserialize` for [`Hasher`], using the encoding of
+//! [`Hasher::to_state_bytes`].
+//!
+//! The `rand_core` feature (disabled by default) adds [`Blake3Rng`], which
+//! implements the [`rand_core`] traits using the keyed extended output.
+//!
+//! The `getrandom` feature (disabled by default, but enabled for [docs.rs])
+//! adds [`Key::generate`], which uses the operating system's random number
+//! generator via the [`getrandom`] crate. This feature implies `std`.
 //!
 //! The NEON implementation is enabled by default for AArch64 but requires the
 //! `neon` feature for other ARM targets. Not all ARMv7 CPUs support NEON, and
@@ -57,12 +77,22 @@
 //! feature name follows the conventions of the RustCrypto [`signature`] crate.)
 //!
 //! [`Hasher::update_rayon`]: struct.Hasher.html#method.update_rayon
+//! [`Hasher::update_rayon_with`]: struct.Hasher.html#method.update_rayon_with
+//! [`Hasher::update_reader_rayon`]: struct.Hasher.html#method.update_reader_rayon
+//! [`OutputReader::fill_rayon`]: struct.OutputReader.html#method.fill_rayon
+//! [`hash_many_rayon`]: fn.hash_many_rayon.html
+//! [`Hasher::update_mmap`]: struct.Hasher.html#method.update_mmap
+//! [`Hasher::update_mmap_rayon`]: struct.Hasher.html#method.update_mmap_rayon
 //! [BLAKE3]: https://blake3.io
 //! [Rayon]: https://github.com/rayon-rs/rayon
 //! [docs.rs]: https://docs.rs/
 //! [`Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
 //! [`Seek`]: https://doc.rust-lang.org/std/io/trait.Seek.html
+//! [`Blake3Rng`]: struct.Blake3Rng.html
 //! [`digest`]: https://crates.io/crates/digest
+//! [`rand_core`]: https://crates.io/crates/rand_core
+//! [`Key::generate`]: struct.Key.html#method.generate
+//! [`getrandom`]: https://crates.io/crates/getrandom
 //! [`signature`]: https://crates.io/crates/signature
 
 #![cfg_attr(not(feature = "std"), no_std)]
@@ -74,11 +104,6 @@ extern crate zeroize_crate as zeroize; // Needed because `zeroize::Zeroize` assu
 #[cfg(test)]
 mod test;
 
-// The guts module is for incremental use cases like the `bao` crate that need
-// to explicitly compute chunk and parent chaining values. It is semi-stable
-// and likely to keep working, but largely undocumented and not intended for
-// widespread use.
-#[doc(hidden)]
 pub mod guts;
 
 /// Undocumented and unstable, for benchmarks only.
@@ -113,10 +138,60 @@ mod sse41;
 #[path = "ffi_sse41.rs"]
 mod sse41;
 
+// The assembly implementations don't have multi-block XOF kernels, so the FFI
+// builds also compile the Rust intrinsics implementations and use them for
+// xof_many. Everything else in them goes unused there.
+#[cfg(blake3_avx2_ffi)]
+#[allow(dead_code)]
+mod rust_avx2;
+#[cfg(blake3_sse2_ffi)]
+#[allow(dead_code)]
+mod rust_sse2;
+#[cfg(blake3_sse41_ffi)]
+#[allow(dead_code)]
+mod rust_sse41;
+
 #[cfg(feature = "traits-preview")]
 pub mod traits;
 
-mod join;
+#[cfg(feature = "std")]
+pub mod consistency;
+#[cfg(feature = "std")]
+pub mod decode;
+#[cfg(feature = "std")]
+pub mod encode;
+
+pub mod join;
+pub mod subtree;
+
+mod batch;
+mod derive_key_context;
+#[cfg(feature = "std")]
+mod hash_tree;
+mod io;
+mod key;
+mod key_tree;
+#[cfg(feature = "std")]
+mod offset;
+#[cfg(feature = "rand_core")]
+mod rng;
+mod state;
+
+pub use batch::{derive_key_many, hash_many, keyed_hash_many};
+#[cfg(feature = "rayon")]
+pub use batch::{derive_key_many_rayon, hash_many_rayon, keyed_hash_many_rayon};
+pub use derive_key_context::DeriveKeyContext;
+#[cfg(feature = "std")]
+pub use hash_tree::HashTree;
+pub use key::Key;
+#[cfg(feature = "std")]
+pub use key_tree::DerivationPath;
+pub use key_tree::{KeyTree, KEY_TREE_CONTEXT};
+#[cfg(feature = "std")]
+pub use offset::{OffsetHasher, OffsetHasherError};
+#[cfg(feature = "rand_core")]
+pub use rng::Blake3Rng;
+pub use state::{StateError, MAX_STATE_LEN};
 
 use arrayref::{array_mut_ref, array_ref};
 use arrayvec::{ArrayString, ArrayVec};
@@ -339,7 +414,7 @@ impl fmt::Debug for Hash {
     }
 }
 
-/// The error type for [`Hash::from_hex`].
+/// The error type for [`Hash::from_hex`] and [`Key::from_hex`].
 ///
 /// The `.to_string()` representation of this error currently distinguishes between bad length
 /// errors and bad character errors. This is to help with logging and debugging, but it isn't a
@@ -373,6 +448,36 @@ impl fmt::Display for HexError {
 #[cfg(feature = "std")]
 impl std::error::Error for HexError {}
 
+/// The error type for [`Hasher::update_rayon_with`], returned when the update
+/// is cancelled.
+///
+/// [`Hasher::update_rayon_with`]: struct.Hasher.html#method.update_rayon_with
+#[cfg(feature = "rayon")]
+#[derive(Clone, Debug)]
+pub struct UpdateCancelled {
+    bytes_hashed: usize,
+}
+
+#[cfg(feature = "rayon")]
+impl UpdateCancelled {
+    /// The number of bytes of input that were hashed before the update was
+    /// cancelled. The `Hasher` has been updated with exactly these bytes, and
+    /// the rest of the input can be passed to another update to resume.
+    pub fn bytes_hashed(&self) -> usize {
+        self.bytes_hashed
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl fmt::Display for UpdateCancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "update cancelled after {} bytes", self.bytes_hashed)
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl std::error::Error for UpdateCancelled {}
+
 // Each chunk or parent node can produce either a 32-byte chaining value or, by
 // setting the ROOT flag, any number of final output bytes. The Output struct
 // captures the state just prior to choosing between those two possibilities.
@@ -418,6 +523,37 @@ impl Output {
             self.flags | ROOT,
         )
     }
+
+    fn root_output_blocks(&self, out: &mut [u8]) {
+        self.platform.xof_many(
+            &self.input_chaining_value,
+            &self.block,
+            self.block_len,
+            self.counter,
+            self.flags | ROOT,
+            out,
+        );
+    }
+
+    // As root_output_blocks, but splitting large outputs in half recursively.
+    // Output blocks are independent, so the halves only need to agree on the
+    // counter. For fill_rayon(), this is where RayonJoin uses multiple threads.
+    fn root_output_blocks_with_join<J: join::Join>(&self, out: &mut [u8]) {
+        // Below this size, the cost of splitting outweighs the benefit.
+        const MIN_SPLIT_LEN: usize = 16 * 1024;
+        if out.len() <= MIN_SPLIT_LEN {
+            self.root_output_blocks(out);
+            return;
+        }
+        let left_blocks = out.len() / BLOCK_LEN / 2;
+        let (left, right) = out.split_at_mut(left_blocks * BLOCK_LEN);
+        let mut right_output = self.clone();
+        right_output.counter += left_blocks as u64;
+        J::join(
+            || self.root_output_blocks_with_join::<J>(left),
+            || right_output.root_output_blocks_with_join::<J>(right),
+        );
+    }
 }
 
 #[derive(Clone)]
@@ -834,6 +970,9 @@ pub fn hash(input: &[u8]) -> Hash {
 /// requirement, and callers need to be careful not to compare MACs as raw
 /// bytes.
 ///
+/// To keep the key in a [`Key`], which zeroizes itself and stays out of logs,
+/// see [`keyed_hash_with`].
+///
 /// For output sizes other than 32 bytes, see [`Hasher::new_keyed`],
 /// [`Hasher::finalize_xof`], and [`OutputReader`].
 ///
@@ -845,6 +984,11 @@ pub fn keyed_hash(key: &[u8; KEY_LEN], input: &[u8]) -> Hash {
     hash_all_at_once::<join::SerialJoin>(input, &key_words, KEYED_HASH).root_hash()
 }
 
+/// As [`keyed_hash`], but with a [`Key`].
+pub fn keyed_hash_with(key: &Key, input: &[u8]) -> Hash {
+    keyed_hash(key.as_bytes(), input)
+}
+
 /// The key derivation function.
 ///
 /// Given cryptographic key material of any length and a context string of any
@@ -872,8 +1016,10 @@ pub fn keyed_hash(key: &[u8; KEY_LEN], input: &[u8]) -> Hash {
 /// [Argon2]. Password hashes are entirely different from generic hash
 /// functions, with opposite design requirements.
 ///
-/// For output sizes other than 32 bytes, see [`Hasher::new_derive_key`],
-/// [`Hasher::finalize_xof`], and [`OutputReader`].
+/// For output sizes other than 32 bytes, see [`derive_key_into`] and
+/// [`derive_key_xof`]. For context strings that aren't UTF-8, see
+/// [`derive_key_raw`]. To hash the context string once and reuse it for many
+/// derivations, see [`DeriveKeyContext`].
 ///
 /// This function is always single-threaded. For multithreading support, see
 /// [`Hasher::new_derive_key`] and
@@ -881,15 +1027,75 @@ pub fn keyed_hash(key: &[u8; KEY_LEN], input: &[u8]) -> Hash {
 ///
 /// [Argon2]: https://en.wikipedia.org/wiki/Argon2
 pub fn derive_key(context: &str, key_material: &[u8]) -> [u8; OUT_LEN] {
-    let context_key =
-        hash_all_at_once::<join::SerialJoin>(context.as_bytes(), IV, DERIVE_KEY_CONTEXT)
-            .root_hash();
-    let context_key_words = platform::words_from_le_bytes_32(context_key.as_bytes());
-    hash_all_at_once::<join::SerialJoin>(key_material, &context_key_words, DERIVE_KEY_MATERIAL)
+    derive_key_output(context.as_bytes(), key_material)
         .root_hash()
         .0
 }
 
+/// As [`derive_key`], but with a context string of raw bytes, which doesn't
+/// need to be UTF-8. This is the same as `blake3_hasher_init_derive_key_raw`
+/// in the C API.
+///
+/// This is for compatibility with existing protocols that use binary
+/// contexts. New applications should prefer a hardcoded `&str` context and
+/// [`derive_key`]. The two functions give the same result when `context` is
+/// the bytes of a string. For other output sizes, see [`derive_key_raw_into`]
+/// and [`derive_key_raw_xof`].
+pub fn derive_key_raw(context: &[u8], key_material: &[u8]) -> [u8; OUT_LEN] {
+    derive_key_output(context, key_material).root_hash().0
+}
+
+/// As [`derive_key`], but filling an output buffer of any length, for example
+/// a 64-byte signing key seed.
+///
+/// The first 32 bytes of output are the same as [`derive_key`], and in
+/// general a shorter output is a prefix of a longer one with the same inputs.
+/// If keys of different lengths are used for different purposes, they should
+/// have different context strings.
+///
+/// # Example
+///
+/// ```
+/// let context = "example.com 2019-12-25 16:18:03 signing seed v1";
+/// let mut seed = [0; 64];
+/// blake3::derive_key_into(context, b"key material", &mut seed);
+/// assert_eq!(seed[..32], blake3::derive_key(context, b"key material"));
+/// ```
+pub fn derive_key_into(context: &str, key_material: &[u8], output: &mut [u8]) {
+    derive_key_xof(context, key_material).fill(output);
+}
+
+/// As [`derive_key`], but returning an [`OutputReader`], which can supply any
+/// number of output bytes. See [`derive_key_into`].
+pub fn derive_key_xof(context: &str, key_material: &[u8]) -> OutputReader {
+    OutputReader::new(derive_key_output(context.as_bytes(), key_material))
+}
+
+/// As [`derive_key_into`], but with a context string of raw bytes. See
+/// [`derive_key_raw`].
+pub fn derive_key_raw_into(context: &[u8], key_material: &[u8], output: &mut [u8]) {
+    derive_key_raw_xof(context, key_material).fill(output);
+}
+
+/// As [`derive_key_xof`], but with a context string of raw bytes. See
+/// [`derive_key_raw`].
+pub fn derive_key_raw_xof(context: &[u8], key_material: &[u8]) -> OutputReader {
+    OutputReader::new(derive_key_output(context, key_material))
+}
+
+// The first stage of key derivation, which hashes the context string into a
+// key for the second stage.
+pub(crate) fn derive_key_context_key(context: &[u8]) -> CVWords {
+    let context_key =
+        hash_all_at_once::<join::SerialJoin>(context, IV, DERIVE_KEY_CONTEXT).root_hash();
+    platform::words_from_le_bytes_32(context_key.as_bytes())
+}
+
+fn derive_key_output(context: &[u8], key_material: &[u8]) -> Output {
+    let context_key_words = derive_key_context_key(context);
+    hash_all_at_once::<join::SerialJoin>(key_material, &context_key_words, DERIVE_KEY_MATERIAL)
+}
+
 fn parent_node_output(
     left_child: &CVBytes,
     right_child: &CVBytes,
@@ -961,6 +1167,11 @@ pub struct Hasher {
     // we don't know whether more input is coming. This is different from how
     // the reference implementation does things.
     cv_stack: ArrayVec<CVBytes, { MAX_DEPTH + 1 }>,
+    // This is always 0 for a regular Hasher. A SubtreeHasher uses a Hasher
+    // internally to hash a subtree that starts somewhere else in the input.
+    // In that case chunk_counter is absolute, but the shape of the CV stack
+    // depends only on the number of chunks since this one.
+    initial_chunk_counter: u64,
 }
 
 impl Hasher {
@@ -969,6 +1180,7 @@ impl Hasher {
             key: *key,
             chunk_state: ChunkState::new(key, 0, flags, Platform::detect()),
             cv_stack: ArrayVec::new(),
+            initial_chunk_counter: 0,
         }
     }
 
@@ -986,17 +1198,26 @@ impl Hasher {
         Self::new_internal(&key_words, KEYED_HASH)
     }
 
+    /// As [`new_keyed`](Hasher::new_keyed), but with a [`Key`].
+    pub fn new_keyed_with(key: &Key) -> Self {
+        Self::new_keyed(key.as_bytes())
+    }
+
     /// Construct a new `Hasher` for the key derivation function. See
     /// [`derive_key`]. The context string should be hardcoded, globally
     /// unique, and application-specific.
     ///
     /// [`derive_key`]: fn.derive_key.html
     pub fn new_derive_key(context: &str) -> Self {
-        let context_key =
-            hash_all_at_once::<join::SerialJoin>(context.as_bytes(), IV, DERIVE_KEY_CONTEXT)
-                .root_hash();
-        let context_key_words = platform::words_from_le_bytes_32(context_key.as_bytes());
-        Self::new_internal(&context_key_words, DERIVE_KEY_MATERIAL)
+        Self::new_derive_key_raw(context.as_bytes())
+    }
+
+    /// As [`new_derive_key`](Hasher::new_derive_key), but with a context
+    /// string of raw bytes. See [`derive_key_raw`].
+    ///
+    /// [`derive_key_raw`]: fn.derive_key_raw.html
+    pub fn new_derive_key_raw(context: &[u8]) -> Self {
+        Self::new_internal(&derive_key_context_key(context), DERIVE_KEY_MATERIAL)
     }
 
     /// Reset the `Hasher` to its initial state.
@@ -1006,7 +1227,7 @@ impl Hasher {
     pub fn reset(&mut self) -> &mut Self {
         self.chunk_state = ChunkState::new(
             &self.key,
-            0,
+            self.initial_chunk_counter,
             self.chunk_state.flags,
             self.chunk_state.platform,
         );
@@ -1024,9 +1245,11 @@ impl Hasher {
     // 1-bits" variant that doesn't require us to retain the subtree size of
     // the CV on top of the stack. The principle is the same: each CV that
     // should remain in the stack is represented by a 1-bit in the total number
-    // of chunks (or bytes) so far.
-    fn merge_cv_stack(&mut self, total_len: u64) {
-        let post_merge_stack_len = total_len.count_ones() as usize;
+    // of chunks (or bytes) so far. The caller passes in an absolute chunk
+    // counter, and we count chunks from initial_chunk_counter.
+    fn merge_cv_stack(&mut self, chunk_counter: u64) {
+        let total_chunks = chunk_counter - self.initial_chunk_counter;
+        let post_merge_stack_len = total_chunks.count_ones() as usize;
         while self.cv_stack.len() > post_merge_stack_len {
             let right_child = self.cv_stack.pop().unwrap();
             let left_child = self.cv_stack.pop().unwrap();
@@ -1097,6 +1320,49 @@ impl Hasher {
         self.update_with_join::<join::SerialJoin>(input)
     }
 
+    /// Add `len` zero bytes to the hash state. This gives exactly the same
+    /// result as passing `len` zero bytes to [`update`](Hasher::update), in
+    /// any mode.
+    ///
+    /// This is a convenience for the holes in sparse files and disk images, so
+    /// that the caller doesn't need a buffer of zeros. It isn't a shortcut: it
+    /// performs every compression that `update` would, so it costs about the
+    /// same as hashing `len` bytes of ordinary input.
+    ///
+    /// This method is single-threaded.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut hasher = blake3::Hasher::new();
+    /// hasher.update(b"foo");
+    /// hasher.update_zeros(1_000_000);
+    /// hasher.update(b"bar");
+    ///
+    /// let mut input = b"foo".to_vec();
+    /// input.resize(1_000_003, 0);
+    /// input.extend_from_slice(b"bar");
+    /// assert_eq!(hasher.finalize(), blake3::hash(&input));
+    /// ```
+    pub fn update_zeros(&mut self, mut len: u64) -> &mut Self {
+        // Large enough to use the widest SIMD implementation.
+        static ZEROS: [u8; 16 * CHUNK_LEN] = [0; 16 * CHUNK_LEN];
+        // Realign to the size of the buffer first, so that every full-size
+        // update afterwards is a complete subtree.
+        let misalignment = (self.count() % ZEROS.len() as u64) as usize;
+        if misalignment != 0 {
+            let take = cmp::min(len, (ZEROS.len() - misalignment) as u64);
+            self.update(&ZEROS[..take as usize]);
+            len -= take;
+        }
+        while len > 0 {
+            let take = cmp::min(len, ZEROS.len() as u64);
+            self.update(&ZEROS[..take as usize]);
+            len -= take;
+        }
+        self
+    }
+
     /// Identical to [`update`](Hasher::update), but using Rayon-based
     /// multithreading internally.
     ///
@@ -1121,7 +1387,273 @@ impl Hasher {
         self.update_with_join::<join::RayonJoin>(input)
     }
 
-    fn update_with_join<J: join::Join>(&mut self, mut input: &[u8]) -> &mut Self {
+    /// As [`update_rayon`](#method.update_rayon), but reporting progress and
+    /// supporting cancellation, for very large inputs like memory-mapped disk
+    /// images.
+    ///
+    /// The input is hashed in steps of `step_len` bytes, rounded up to a power
+    /// of two and to at least one chunk. (A `step_len` above the largest power
+    /// of two that fits in a `usize` is rounded down to that instead.) Each
+    /// step is multithreaded internally as with `update_rayon`. Steps are
+    /// aligned to the total input so far, so hashing in steps doesn't reduce
+    /// parallelism, as long as `step_len` is large compared to the number of
+    /// threads times the chunk size. A few megabytes or more is a good choice.
+    ///
+    /// After each step, `progress` is called on the calling thread with the
+    /// number of bytes of `input` hashed so far. Before each step, `cancel` is
+    /// checked, and if it's `true` this method returns [`UpdateCancelled`]
+    /// without hashing any more input. In that case the `Hasher` has been
+    /// updated with exactly the first
+    /// [`bytes_hashed`](UpdateCancelled::bytes_hashed) bytes of `input`, and it
+    /// can be finalized or resumed with the rest of the input later.
+    ///
+    /// This method is gated by the `rayon` Cargo feature, which is disabled by
+    /// default but enabled on [docs.rs](https://docs.rs).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), blake3::UpdateCancelled> {
+    /// use std::sync::atomic::AtomicBool;
+    ///
+    /// let input = vec![0xab; 1 << 24];
+    /// let cancel = AtomicBool::new(false);
+    /// let mut hasher = blake3::Hasher::new();
+    /// hasher.update_rayon_with(&input, 1 << 20, &cancel, |done| {
+    ///     println!("{:.0}%", 100.0 * done as f64 / input.len() as f64);
+    /// })?;
+    /// assert_eq!(hasher.finalize(), blake3::hash(&input));
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "rayon")]
+    pub fn update_rayon_with(
+        &mut self,
+        input: &[u8],
+        step_len: usize,
+        cancel: &core::sync::atomic::AtomicBool,
+        mut progress: impl FnMut(usize),
+    ) -> Result<&mut Self, UpdateCancelled> {
+        // The largest power of two that fits in a usize.
+        const MAX_STEP_LEN: usize = 1 << (usize::BITS - 1);
+        let step_len = cmp::max(step_len, CHUNK_LEN)
+            .checked_next_power_of_two()
+            .unwrap_or(MAX_STEP_LEN);
+        let mut bytes_hashed = 0;
+        while bytes_hashed < input.len() {
+            if cancel.load(core::sync::atomic::Ordering::Relaxed) {
+                return Err(UpdateCancelled { bytes_hashed });
+            }
+            // Align the end of this step to a multiple of step_len in the
+            // total input, so that each step after the first is a complete
+            // subtree.
+            let misalignment = (self.count() & (step_len as u64 - 1)) as usize;
+            let take = cmp::min(step_len - misalignment, input.len() - bytes_hashed);
+            self.update_rayon(&input[bytes_hashed..][..take]);
+            bytes_hashed += take;
+            progress(bytes_hashed);
+        }
+        Ok(self)
+    }
+
+    /// As [`update`](Hasher::update), but reading from a
+    /// [`std::io::Read`](std::io::Read) implementation.
+    ///
+    /// `Hasher` implements [`std::io::Write`], so it's possible to use
+    /// [`std::io::copy`] to update a `Hasher` from any reader. However,
+    /// `copy` currently uses an internal 8 KiB buffer, which isn't large
+    /// enough to take advantage of all SIMD instruction sets. (AVX-512 in
+    /// particular needs 16 KiB.) `update_reader` avoids that problem by
+    /// reading into a buffer of at least 64 KiB. The exact buffer size might
+    /// change at any time, but it will always be large enough for all of this
+    /// crate's SIMD implementations.
+    ///
+    /// If the reader returns an error, that error is returned here, and the
+    /// `Hasher` keeps whatever input it received before the error. Errors of
+    /// kind [`Interrupted`](std::io::ErrorKind::Interrupted) are retried.
+    ///
+    /// For hashing files, memory mapping can be faster than this method. See
+    /// [`update_mmap`](Hasher::update_mmap) and
+    /// [`update_mmap_rayon`](Hasher::update_mmap_rayon).
+    ///
+    /// This method requires the `std` Cargo feature, which is enabled by
+    /// default.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # fn main() -> std::io::Result<()> {
+    /// // Hash standard input.
+    /// let mut hasher = blake3::Hasher::new();
+    /// hasher.update_reader(std::io::stdin().lock())?;
+    /// println!("{}", hasher.finalize());
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`std::io::Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
+    /// [`std::io::copy`]: https://doc.rust-lang.org/std/io/fn.copy.html
+    #[cfg(feature = "std")]
+    pub fn update_reader(&mut self, reader: impl std::io::Read) -> std::io::Result<&mut Self> {
+        io::copy_wide(reader, self)?;
+        Ok(self)
+    }
+
+    /// As [`update_reader`](Hasher::update_reader), but using Rayon-based
+    /// multithreading internally.
+    ///
+    /// This method uses double buffering: while the Rayon thread pool hashes
+    /// one buffer with [`update_rayon`](Hasher::update_rayon), the next
+    /// buffer is read at the same time. That keeps the worker threads busy
+    /// while waiting on IO, so that pipes, sockets, and files that can't be
+    /// memory mapped can be hashed with multiple threads. The buffers are
+    /// currently 1 MiB each, allocated on the heap, but that might change at
+    /// any time. Because reading happens on a Rayon worker thread, the reader
+    /// must be [`Send`].
+    ///
+    /// As with [`update_rayon`](Hasher::update_rayon), multithreading is only
+    /// worth it for long inputs. For short inputs, this method is slower than
+    /// [`update_reader`](Hasher::update_reader). Also, if reading is slower
+    /// than hashing, as it is for most network connections and spinning
+    /// disks, multithreading won't help, and benchmarking your specific use
+    /// case is important.
+    ///
+    /// Error handling is the same as with
+    /// [`update_reader`](Hasher::update_reader).
+    ///
+    /// This method is gated by the `rayon` Cargo feature, which is disabled by
+    /// default but enabled on [docs.rs](https://docs.rs).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # fn main() -> std::io::Result<()> {
+    /// // Hash standard input. Note that StdinLock isn't Send, but Stdin is.
+    /// let mut hasher = blake3::Hasher::new();
+    /// hasher.update_reader_rayon(std::io::stdin())?;
+    /// println!("{}", hasher.finalize());
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "rayon")]
+    pub fn update_reader_rayon(
+        &mut self,
+        reader: impl std::io::Read + Send,
+    ) -> std::io::Result<&mut Self> {
+        io::copy_wide_rayon(reader, self)?;
+        Ok(self)
+    }
+
+    /// As [`update`](Hasher::update), but reading the contents of a file
+    /// using memory mapping.
+    ///
+    /// Not all files can be memory mapped, and memory mapping small files can
+    /// be slower than reading them the usual way. In those cases, this method
+    /// falls back to regular buffered reads. Currently the fallback happens
+    /// for files that aren't regular files (like pipes or `/proc` files), for
+    /// files that are empty or shorter than 16 KiB, and for files too long to
+    /// map on this platform. These heuristics might change at any time.
+    ///
+    /// Like [`update`](Hasher::update), this method is single-threaded. For
+    /// large files, the multithreaded
+    /// [`update_mmap_rayon`](Hasher::update_mmap_rayon) can be much faster.
+    ///
+    /// This method takes a path rather than an open
+    /// [`File`](std::fs::File), because reading from a memory map ignores the
+    /// seek position of a file handle, and it would be confusing for this
+    /// method to behave differently from reading the same handle. It always
+    /// hashes the entire file.
+    ///
+    /// This method is gated by the `mmap` Cargo feature, which is disabled by
+    /// default but enabled on [docs.rs](https://docs.rs).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut hasher = blake3::Hasher::new();
+    /// hasher.update_mmap("file.dat")?;
+    /// println!("{}", hasher.finalize());
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "mmap")]
+    pub fn update_mmap(&mut self, path: impl AsRef<std::path::Path>) -> std::io::Result<&mut Self> {
+        let file = std::fs::File::open(path.as_ref())?;
+        if let Some(mmap) = io::maybe_mmap_file(&file)? {
+            self.update(&mmap);
+        } else {
+            io::copy_wide(&file, self)?;
+        }
+        Ok(self)
+    }
+
+    /// As [`update_rayon`](Hasher::update_rayon), but reading the contents of
+    /// a file using memory mapping. This is what `b3sum` does by default.
+    ///
+    /// The same fallbacks apply as for [`update_mmap`](Hasher::update_mmap).
+    /// Files that can't be mapped are hashed with the double-buffered reads
+    /// of [`update_reader_rayon`](Hasher::update_reader_rayon).
+    ///
+    /// See [`update_rayon`](Hasher::update_rayon) for notes about when
+    /// multithreading is worth it. Memory mapping a file and hashing it with
+    /// multiple threads is very fast when the file is in the page cache or on
+    /// an SSD, but on a spinning disk the random access pattern can lead to
+    /// thrashing. Benchmarking your specific use case is important.
+    ///
+    /// This method is gated by both the `mmap` and `rayon` Cargo features,
+    /// which are disabled by default but enabled on [docs.rs](https://docs.rs).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # fn main() -> std::io::Result<()> {
+    /// let mut hasher = blake3::Hasher::new();
+    /// hasher.update_mmap_rayon("big_file.dat")?;
+    /// println!("{}", hasher.finalize());
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "mmap")]
+    #[cfg(feature = "rayon")]
+    pub fn update_mmap_rayon(
+        &mut self,
+        path: impl AsRef<std::path::Path>,
+    ) -> std::io::Result<&mut Self> {
+        let file = std::fs::File::open(path.as_ref())?;
+        if let Some(mmap) = io::maybe_mmap_file(&file)? {
+            self.update_rayon(&mmap);
+        } else {
+            io::copy_wide_rayon(&file, self)?;
+        }
+        Ok(self)
+    }
+
+    /// As [`update`](Hasher::update), but with the multithreading strategy
+    /// chosen by the [`Join`](join::Join) type parameter.
+    ///
+    /// [`update`](Hasher::update) is equivalent to
+    /// `update_with_join::<SerialJoin>`, and
+    /// [`update_rayon`](#method.update_rayon) is equivalent to
+    /// `update_with_join::<RayonJoin>`. [`ThreadJoin`](join::ThreadJoin) uses
+    /// standard library threads instead, and callers can implement `Join` to
+    /// use their own executor. The resulting hash is the same with any `Join`
+    /// implementation. See the [`join`] module for details.
+    ///
+    /// As with `update_rayon`, the input buffer needs to be large to get any
+    /// benefit from multithreading.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[cfg(feature = "std")] {
+    /// let input = vec![0xab; 1 << 20];
+    /// let mut hasher = blake3::Hasher::new();
+    /// hasher.update_with_join::<blake3::join::ThreadJoin>(&input);
+    /// assert_eq!(hasher.finalize(), blake3::hash(&input));
+    /// # }
+    /// ```
+    pub fn update_with_join<J: join::Join>(&mut self, mut input: &[u8]) -> &mut Self {
         // If we have some partial chunk bytes in the internal chunk_state, we
         // need to finish that chunk first.
         if self.chunk_state.len() > 0 {
@@ -1164,7 +1696,8 @@ impl Hasher {
             debug_assert_eq!(self.chunk_state.len(), 0, "no partial chunk data");
             debug_assert_eq!(CHUNK_LEN.count_ones(), 1, "power of 2 chunk len");
             let mut subtree_len = largest_power_of_two_leq(input.len());
-            let count_so_far = self.chunk_state.chunk_counter * CHUNK_LEN as u64;
+            let count_so_far =
+                (self.chunk_state.chunk_counter - self.initial_chunk_counter) * CHUNK_LEN as u64;
             // Shrink the subtree_len until it evenly divides the count so far.
             // We know that subtree_len itself is a power of 2, so we can use a
             // bitmasking trick instead of an actual remainder operation. (Note
@@ -1246,7 +1779,7 @@ impl Hasher {
         // also. Convert it directly into an Output. Otherwise, we need to
         // merge subtrees below.
         if self.cv_stack.is_empty() {
-            debug_assert_eq!(self.chunk_state.chunk_counter, 0);
+            debug_assert_eq!(self.chunk_state.chunk_counter, self.initial_chunk_counter);
             return self.chunk_state.output();
         }
 
@@ -1267,7 +1800,7 @@ impl Hasher {
         if self.chunk_state.len() > 0 {
             debug_assert_eq!(
                 self.cv_stack.len(),
-                self.chunk_state.chunk_counter.count_ones() as usize,
+                (self.chunk_state.chunk_counter - self.initial_chunk_counter).count_ones() as usize,
                 "cv stack does not need a merge"
             );
             output = self.chunk_state.output();
@@ -1317,7 +1850,52 @@ impl Hasher {
 
     /// Return the total number of bytes hashed so far.
     pub fn count(&self) -> u64 {
-        self.chunk_state.chunk_counter * CHUNK_LEN as u64 + self.chunk_state.len() as u64
+        let chunks = self.chunk_state.chunk_counter - self.initial_chunk_counter;
+        chunks * CHUNK_LEN as u64 + self.chunk_state.len() as u64
+    }
+
+    /// Serialize the complete state of the `Hasher`, so that hashing can be
+    /// resumed later, possibly in another process or on another machine, with
+    /// [`from_state_bytes`](Hasher::from_state_bytes).
+    ///
+    /// The encoding is versioned and stable. It includes the mode of the
+    /// `Hasher` (regular, keyed, or key derivation) and its key, so **the
+    /// state of a keyed `Hasher` is as secret as the key itself**. It's at
+    /// most [`MAX_STATE_LEN`] bytes long.
+    ///
+    /// With the `serde` Cargo feature, `Hasher` also implements `Serialize`
+    /// and `Deserialize` using this encoding.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # fn main() -> Result<(), blake3::StateError> {
+    /// let mut hasher = blake3::Hasher::new();
+    /// hasher.update(b"foo");
+    /// let state = hasher.to_state_bytes();
+    ///
+    /// let mut resumed = blake3::Hasher::from_state_bytes(&state)?;
+    /// resumed.update(b"bar");
+    /// assert_eq!(resumed.finalize(), blake3::hash(b"foobar"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn to_state_bytes(&self) -> ArrayVec<u8, MAX_STATE_LEN> {
+        state::encode(self)
+    }
+
+    /// Restore a `Hasher` from the output of
+    /// [`to_state_bytes`](Hasher::to_state_bytes). Hashing continues exactly
+    /// where it left off.
+    ///
+    /// This returns an error if the state is from an unsupported version of
+    /// the encoding, or if it's corrupt or internally inconsistent, for
+    /// example if the number of chaining values doesn't match the number of
+    /// bytes hashed so far. Note that the chaining values themselves can't be
+    /// checked, so a state with modified chaining values but valid structure
+    /// will be accepted, and it will produce the wrong hash.
+    pub fn from_state_bytes(bytes: &[u8]) -> Result<Self, StateError> {
+        state::decode(bytes)
     }
 }
 
@@ -1399,26 +1977,73 @@ impl OutputReader {
     /// calling `fill` repeatedly with a short-length or odd-length slice will
     /// end up performing the same compression multiple times. If you're
     /// reading output in a loop, prefer a slice length that's a multiple of
-    /// 64.
+    /// 64. Longer slices are also faster, because whole blocks of output are
+    /// computed several at a time with SIMD, where the platform supports it.
     ///
     /// The maximum output size of BLAKE3 is 2<sup>64</sup>-1 bytes. If you try
     /// to extract more than that, for example by seeking near the end and
     /// reading further, the behavior is unspecified.
     ///
     /// [`Read::read`]: #method.read
-    pub fn fill(&mut self, mut buf: &mut [u8]) {
-        while !buf.is_empty() {
-            let block: [u8; BLOCK_LEN] = self.inner.root_output_block();
-            let output_bytes = &block[self.position_within_block as usize..];
-            let take = cmp::min(buf.len(), output_bytes.len());
-            buf[..take].copy_from_slice(&output_bytes[..take]);
-            buf = &mut buf[take..];
-            self.position_within_block += take as u8;
-            if self.position_within_block == BLOCK_LEN as u8 {
-                self.inner.counter += 1;
-                self.position_within_block = 0;
-            }
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        self.fill_with_join::<join::SerialJoin>(buf);
+    }
+
+    /// Identical to [`fill`](OutputReader::fill), but using Rayon-based
+    /// multithreading internally. The output is split across threads by block
+    /// counter, and the bytes are exactly the same as `fill` would produce,
+    /// from any starting position.
+    ///
+    /// This method is gated by the `rayon` Cargo feature, which is disabled by
+    /// default but enabled on [docs.rs](https://docs.rs).
+    ///
+    /// As with [`Hasher::update_rayon`], the buffer needs to be large to get
+    /// any benefit from multithreading, and it's important to benchmark your
+    /// specific use case.
+    #[cfg(feature = "rayon")]
+    pub fn fill_rayon(&mut self, buf: &mut [u8]) {
+        self.fill_with_join::<join::RayonJoin>(buf);
+    }
+
+    fn fill_with_join<J: join::Join>(&mut self, mut buf: &mut [u8]) {
+        if buf.is_empty() {
+            return;
+        }
+
+        // If we're partway through a block, finish that block first.
+        if self.position_within_block != 0 {
+            buf = self.fill_one_block(buf);
+        }
+
+        // Write whole blocks directly into the caller's buffer. The platform
+        // computes as many of these in parallel as it can.
+        let full_blocks = buf.len() / BLOCK_LEN;
+        if full_blocks > 0 {
+            let (full, rest) = buf.split_at_mut(full_blocks * BLOCK_LEN);
+            self.inner.root_output_blocks_with_join::<J>(full);
+            self.inner.counter += full_blocks as u64;
+            buf = rest;
+        }
+
+        // Finally, any partial block at the end.
+        if !buf.is_empty() {
+            self.fill_one_block(buf);
+        }
+    }
+
+    // Copy output from the current block, up to the end of the block, and
+    // return the rest of the buffer.
+    fn fill_one_block<'a>(&mut self, buf: &'a mut [u8]) -> &'a mut [u8] {
+        let block: [u8; BLOCK_LEN] = self.inner.root_output_block();
+        let output_bytes = &block[self.position_within_block as usize..];
+        let take = cmp::min(buf.len(), output_bytes.len());
+        buf[..take].copy_from_slice(&output_bytes[..take]);
+        self.position_within_block += take as u8;
+        if self.position_within_block == BLOCK_LEN as u8 {
+            self.inner.counter += 1;
+            self.position_within_block = 0;
         }
+        &mut buf[take..]
     }
 
     /// Return the current read position in the output stream. This is
@@ -0,0 +1,618 @@
+//! `OffsetHasher`, for hashing input that arrives out of order.
+
+use crate::{
+    compress_subtree_to_parent_node, join, parent_node_output, platform, subtree::Mode, CVBytes,
+    CVWords, ChunkState, Hash, Output, OutputReader, CHUNK_LEN, IV,
+};
+use arrayref::array_ref;
+use core::cmp;
+use core::fmt;
+use core::ops::Range;
+use platform::Platform;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// A hasher that accepts input at arbitrary offsets, in any order, from any
+/// number of threads.
+///
+/// The total length of the input must be known up front. Each call to
+/// [`write`](OffsetHasher::write) supplies the bytes for one range of the
+/// input, and ranges may not overlap. When all the input has arrived,
+/// [`finalize`](OffsetHasher::finalize) returns the same hash as
+/// [`hash`](crate::hash) (or [`keyed_hash`](crate::keyed_hash), or
+/// [`derive_key`](crate::derive_key)) would for the assembled input.
+///
+/// Whole chunks and subtrees are compressed as soon as their input is
+/// complete, with the same SIMD optimizations that
+/// [`Hasher::update`](crate::Hasher::update) uses, and without holding any
+/// locks. Only the chunks at the edges of the ranges received so far are
+/// buffered, along with the chaining values of finished subtrees that are
+/// waiting for their neighbors, so memory use depends on how fragmented the
+/// input is, not on its length. Each write should cover at least a few KiB of
+/// aligned input for the best performance.
+///
+/// This type is gated by the `std` Cargo feature, which is enabled by
+/// default.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), blake3::OffsetHasherError> {
+/// let input = vec![0xab; 1_000_000];
+/// let hasher = blake3::OffsetHasher::new(input.len() as u64);
+/// std::thread::scope(|scope| {
+///     // Hash the two halves of the input on two different threads.
+///     let (first, second) = input.split_at(500_000);
+///     let hasher = &hasher;
+///     let t1 = scope.spawn(move || hasher.write(500_000, second));
+///     let t2 = scope.spawn(move || hasher.write(0, first));
+///     t1.join().unwrap().and(t2.join().unwrap())
+/// })?;
+/// assert_eq!(hasher.finalize()?, blake3::hash(&input));
+/// # Ok(())
+/// # }
+/// ```
+pub struct OffsetHasher {
+    key: CVWords,
+    flags: u8,
+    platform: Platform,
+    total_len: u64,
+    total_chunks: u64,
+    // The level of the root node in the tree, where chunks are level 0. The
+    // two children of the root are never merged until finalize().
+    root_level: u32,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    // The ranges of input claimed by writes so far, including writes that are
+    // still in progress, coalesced, as start => end. New writes can't overlap
+    // these.
+    reserved: BTreeMap<u64, u64>,
+    // The ranges of input whose writes have finished, coalesced, as start =>
+    // end. A write compresses its input outside the lock, so a range is only
+    // added here once its CVs or buffered bytes are stored below. Until then,
+    // finalize() reports it as missing.
+    received: BTreeMap<u64, u64>,
+    // Chunks that have received some of their input but not all of it, as
+    // chunk index => (buffer, number of bytes received). If the whole input
+    // is a single chunk, it stays here until finalize().
+    partial_chunks: BTreeMap<u64, (Box<[u8; CHUNK_LEN]>, usize)>,
+    // Chaining values of finished subtrees waiting for their siblings, as
+    // (level, index within that level) => CV.
+    cvs: BTreeMap<(u32, u64), CVBytes>,
+}
+
+impl OffsetHasher {
+    fn new_internal(key: &CVWords, flags: u8, total_len: u64) -> Self {
+        let total_chunks = chunks_rounded_up(total_len);
+        Self {
+            key: *key,
+            flags,
+            platform: Platform::detect(),
+            total_len,
+            total_chunks,
+            root_level: total_chunks.next_power_of_two().trailing_zeros(),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Construct a new `OffsetHasher` for the regular hash function, for an
+    /// input of `total_len` bytes.
+    pub fn new(total_len: u64) -> Self {
+        Self::new_internal(IV, 0, total_len)
+    }
+
+    /// Construct a new `OffsetHasher` for an input of `total_len` bytes, with
+    /// the keyed hash function or the key derivation function. Build the
+    /// [`Mode`] once and reuse it, because [`Mode::derive_key`] hashes the
+    /// context string.
+    pub fn with_mode(mode: &Mode, total_len: u64) -> Self {
+        Self::new_internal(&mode.key, mode.flags, total_len)
+    }
+
+    /// The total length of the input, as given to the constructor.
+    pub fn total_len(&self) -> u64 {
+        self.total_len
+    }
+
+    /// Supply the input bytes starting at `offset`.
+    ///
+    /// This returns an error if the range extends past the total length of
+    /// the input, or if it overlaps with a range that was already written.
+    /// In that case, none of `input` is used.
+    pub fn write(&self, offset: u64, input: &[u8]) -> Result<(), OffsetHasherError> {
+        let end = match offset.checked_add(input.len() as u64) {
+            Some(end) if end <= self.total_len => end,
+            _ => {
+                return Err(OffsetHasherError(ErrorInner::OutOfBounds {
+                    offset,
+                    len: input.len() as u64,
+                }))
+            }
+        };
+        if input.is_empty() {
+            return Ok(());
+        }
+        self.state.lock().unwrap().reserve(offset, end)?;
+
+        // Find the chunks that this write covers completely. The last chunk
+        // of the input might be short.
+        let chunk_len = CHUNK_LEN as u64;
+        let mut whole_start = chunks_rounded_up(offset);
+        let whole_end = if end == self.total_len {
+            self.total_chunks
+        } else {
+            end / chunk_len
+        };
+        if self.root_level == 0 || whole_start >= whole_end {
+            // No whole chunks (or the input is a single chunk, which has to
+            // wait for finalize()). Buffer everything.
+            let mut state = self.state.lock().unwrap();
+            self.buffer_partial(&mut state, offset, input);
+            insert_range(&mut state.received, offset, end);
+            return Ok(());
+        }
+
+        // Compress the whole chunks as the largest subtrees we can, outside
+        // the lock. The root node and its children are handled separately, so
+        // the largest subtree we compress here is one of the root's children.
+        let max_level = self.root_level - 1;
+        let mut cvs = Vec::new();
+        while whole_start < whole_end {
+            let mut level = max_level;
+            if whole_start != 0 {
+                level = cmp::min(level, whole_start.trailing_zeros());
+            }
+            while whole_start + (1 << level) > whole_end {
+                level -= 1;
+            }
+            let subtree_start = (whole_start * chunk_len - offset) as usize;
+            let subtree_end =
+                (cmp::min((whole_start + (1 << level)) * chunk_len, end) - offset) as usize;
+            let cv = self.subtree_cv(&input[subtree_start..subtree_end], whole_start);
+            cvs.push((level, whole_start >> level, cv));
+            whole_start += 1 << level;
+        }
+
+        let whole_end_byte = cmp::min(whole_end * chunk_len, end);
+        let head = &input[..(chunks_rounded_up(offset) * chunk_len - offset) as usize];
+        let tail = &input[(whole_end_byte - offset) as usize..];
+        let mut state = self.state.lock().unwrap();
+        self.buffer_partial(&mut state, offset, head);
+        self.buffer_partial(&mut state, whole_end_byte, tail);
+        for (level, index, cv) in cvs {
+            self.insert_cv(&mut state, level, index, cv);
+        }
+        insert_range(&mut state.received, offset, end);
+        Ok(())
+    }
+
+    // Copy input into the buffers of the chunks it partially covers, and
+    // compress any chunks that become complete.
+    fn buffer_partial(&self, state: &mut State, mut offset: u64, mut input: &[u8]) {
+        while !input.is_empty() {
+            let chunk_index = offset / CHUNK_LEN as u64;
+            let chunk_start = chunk_index * CHUNK_LEN as u64;
+            let offset_in_chunk = (offset - chunk_start) as usize;
+            let take = cmp::min(input.len(), CHUNK_LEN - offset_in_chunk);
+            let (buf, received) = state
+                .partial_chunks
+                .entry(chunk_index)
+                .or_insert_with(|| (Box::new([0; CHUNK_LEN]), 0));
+            buf[offset_in_chunk..][..take].copy_from_slice(&input[..take]);
+            *received += take;
+            let chunk_len = cmp::min(CHUNK_LEN as u64, self.total_len - chunk_start) as usize;
+            if *received == chunk_len && self.root_level > 0 {
+                let (buf, _) = state.partial_chunks.remove(&chunk_index).unwrap();
+                let cv = self.subtree_cv(&buf[..chunk_len], chunk_index);
+                self.insert_cv(state, 0, chunk_index, cv);
+            }
+            offset += take as u64;
+            input = &input[take..];
+        }
+    }
+
+    // The non-root chaining value of a subtree, which is never the root node.
+    fn subtree_cv(&self, input: &[u8], chunk_counter: u64) -> CVBytes {
+        if input.len() <= CHUNK_LEN {
+            let mut chunk_state =
+                ChunkState::new(&self.key, chunk_counter, self.flags, self.platform);
+            chunk_state.update(input);
+            return chunk_state.output().chaining_value();
+        }
+        let cv_pair = compress_subtree_to_parent_node::<join::SerialJoin>(
+            input,
+            &self.key,
+            chunk_counter,
+            self.flags,
+            self.platform,
+        );
+        self.parent_output(array_ref!(cv_pair, 0, 32), array_ref!(cv_pair, 32, 32))
+            .chaining_value()
+    }
+
+    fn parent_output(&self, left: &CVBytes, right: &CVBytes) -> Output {
+        parent_node_output(left, right, &self.key, self.flags, self.platform)
+    }
+
+    // Add the CV of a finished subtree, and merge it with its sibling if the
+    // sibling is already finished. Repeat up the tree.
+    fn insert_cv(&self, state: &mut State, mut level: u32, mut index: u64, mut cv: CVBytes) {
+        loop {
+            debug_assert!(level < self.root_level);
+            let is_left_child = index & 1 == 0;
+            // A left child with no right sibling, at the right edge of the
+            // tree, takes the place of its parent.
+            if is_left_child && (index + 1) << level >= self.total_chunks {
+                level += 1;
+                index /= 2;
+                continue;
+            }
+            if level + 1 == self.root_level {
+                state.cvs.insert((level, index), cv);
+                return;
+            }
+            let Some(sibling) = state.cvs.remove(&(level, index ^ 1)) else {
+                state.cvs.insert((level, index), cv);
+                return;
+            };
+            let (left, right) = if is_left_child {
+                (&cv, &sibling)
+            } else {
+                (&sibling, &cv)
+            };
+            cv = self.parent_output(left, right).chaining_value();
+            level += 1;
+            index /= 2;
+        }
+    }
+
+    fn final_output(&self) -> Result<Output, OffsetHasherError> {
+        let state = self.state.lock().unwrap();
+        let holes = state.holes(self.total_len);
+        if !holes.is_empty() {
+            return Err(OffsetHasherError(ErrorInner::Missing(holes)));
+        }
+        // With no holes, all of the input should be stored below. If it isn't,
+        // report the input that's missing rather than panicking or returning
+        // the wrong hash.
+        let missing = |range: Range<u64>| OffsetHasherError(ErrorInner::Missing(vec![range]));
+        if self.root_level == 0 {
+            // The input is a single chunk, possibly empty.
+            let mut chunk_state = ChunkState::new(&self.key, 0, self.flags, self.platform);
+            let received = match state.partial_chunks.get(&0) {
+                Some((buf, received)) => &buf[..*received],
+                None => &[],
+            };
+            if received.len() as u64 != self.total_len {
+                return Err(missing(received.len() as u64..self.total_len));
+            }
+            chunk_state.update(received);
+            return Ok(chunk_state.output());
+        }
+        let child_level = self.root_level - 1;
+        let child_len = (CHUNK_LEN as u64) << child_level;
+        let left = state.cvs.get(&(child_level, 0));
+        let right = state.cvs.get(&(child_level, 1));
+        match (left, right) {
+            (Some(left), Some(right)) => Ok(self.parent_output(left, right)),
+            (None, _) => Err(missing(0..child_len)),
+            (_, None) => Err(missing(child_len..self.total_len)),
+        }
+    }
+
+    /// Finalize the hash of the input and return the [`Hash`]. This returns
+    /// an error listing the missing ranges if any of the input hasn't been
+    /// written yet. Writes that are still in progress on other threads count
+    /// as missing.
+    ///
+    /// This method is idempotent. Calling it twice will give the same result.
+    pub fn finalize(&self) -> Result<Hash, OffsetHasherError> {
+        Ok(self.final_output()?.root_hash())
+    }
+
+    /// Finalize the hash of the input and return an [`OutputReader`], which
+    /// can supply any number of output bytes. This returns an error listing
+    /// the missing ranges if any of the input hasn't been written yet. Writes
+    /// that are still in progress on other threads count as missing.
+    ///
+    /// This method is idempotent. Calling it twice will give the same result.
+    pub fn finalize_xof(&self) -> Result<OutputReader, OffsetHasherError> {
+        Ok(OutputReader::new(self.final_output()?))
+    }
+}
+
+// The number of chunks needed to hold len bytes. This is also the index of the
+// first chunk that starts at or after offset len. CHUNK_LEN is a power of two,
+// and this avoids overflow near u64::MAX.
+fn chunks_rounded_up(len: u64) -> u64 {
+    let partial = len & (CHUNK_LEN as u64 - 1) != 0;
+    len / CHUNK_LEN as u64 + partial as u64
+}
+
+// Add a range that doesn't overlap any others to a map of coalesced ranges,
+// merging it with its neighbors if they touch.
+fn insert_range(ranges: &mut BTreeMap<u64, u64>, start: u64, end: u64) {
+    let mut new_start = start;
+    let mut new_end = end;
+    if let Some((&s, &e)) = ranges.range(..=start).next_back() {
+        if e == start {
+            ranges.remove(&s);
+            new_start = s;
+        }
+    }
+    if let Some(e) = ranges.remove(&end) {
+        new_end = e;
+    }
+    ranges.insert(new_start, new_end);
+}
+
+impl State {
+    // Claim a range for a new write, or return an error if it overlaps with
+    // anything claimed before.
+    fn reserve(&mut self, start: u64, end: u64) -> Result<(), OffsetHasherError> {
+        let before = self.reserved.range(..=start).next_back();
+        let after = self.reserved.range(start..).next();
+        let overlaps = matches!(before, Some((_, &e)) if e > start)
+            || matches!(after, Some((&s, _)) if s < end);
+        if overlaps {
+            return Err(OffsetHasherError(ErrorInner::Overlap {
+                offset: start,
+                len: end - start,
+            }));
+        }
+        insert_range(&mut self.reserved, start, end);
+        Ok(())
+    }
+
+    fn holes(&self, total_len: u64) -> Vec<Range<u64>> {
+        let mut holes = Vec::new();
+        let mut position = 0;
+        for (&start, &end) in &self.received {
+            if start > position {
+                holes.push(position..start);
+            }
+            position = end;
+        }
+        if position < total_len {
+            holes.push(position..total_len);
+        }
+        holes
+    }
+}
+
+// Don't derive(Debug), because the state may be secret.
+impl fmt::Debug for OffsetHasher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OffsetHasher")
+            .field("flags", &self.flags)
+            .field("platform", &self.platform)
+            .field("total_len", &self.total_len)
+            .finish()
+    }
+}
+
+/// The error type for [`OffsetHasher`].
+///
+/// The `.to_string()` representation of this error currently describes the
+/// offending range, or lists the missing ranges. This is to help with logging
+/// and debugging, but it isn't a stable API detail, and it may change at any
+/// time. Use [`missing_ranges`](OffsetHasherError::missing_ranges) to get the
+/// missing ranges programmatically.
+#[derive(Clone, Debug)]
+pub struct OffsetHasherError(ErrorInner);
+
+#[derive(Clone, Debug)]
+enum ErrorInner {
+    OutOfBounds { offset: u64, len: u64 },
+    Overlap { offset: u64, len: u64 },
+    Missing(Vec<Range<u64>>),
+}
+
+impl OffsetHasherError {
+    /// The ranges of input that were never written, or whose writes hadn't
+    /// finished yet, if this error came from finalizing an incomplete input.
+    /// Otherwise this is empty.
+    pub fn missing_ranges(&self) -> &[Range<u64>] {
+        match &self.0 {
+            ErrorInner::Missing(holes) => holes,
+            _ => &[],
+        }
+    }
+}
+
+impl fmt::Display for OffsetHasherError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            ErrorInner::OutOfBounds { offset, len } => write!(
+                f,
+                "write of {} bytes at offset {} is past the end of the input",
+                len, offset
+            ),
+            ErrorInner::Overlap { offset, len } => write!(
+                f,
+                "write of {} bytes at offset {} overlaps a previous write",
+                len, offset
+            ),
+            ErrorInner::Missing(holes) => {
+                write!(f, "missing input:")?;
+                for hole in holes {
+                    write!(f, " {}..{}", hole.start, hole.end)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for OffsetHasherError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{paint_test_input, TEST_CASES, TEST_KEY};
+    use rand::prelude::*;
+
+    // Split the input into pieces at random offsets, both aligned and
+    // unaligned, and write them in a random order from several threads.
+    fn write_shuffled(hasher: &OffsetHasher, input: &[u8], rng: &mut impl Rng) {
+        let mut boundaries = vec![0, input.len()];
+        for _ in 0..rng.gen_range(0..8) {
+            let boundary = if rng.gen() {
+                rng.gen_range(0..=input.len() / CHUNK_LEN) * CHUNK_LEN
+            } else {
+                rng.gen_range(0..=input.len())
+            };
+            boundaries.push(boundary);
+        }
+        boundaries.sort_unstable();
+        boundaries.dedup();
+        let mut pieces: Vec<_> = boundaries.windows(2).map(|w| (w[0], w[1])).collect();
+        pieces.shuffle(rng);
+        std::thread::scope(|scope| {
+            for chunk in pieces.chunks(2) {
+                scope.spawn(move || {
+                    for &(start, end) in chunk {
+                        hasher.write(start as u64, &input[start..end]).unwrap();
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_offset_hasher_all_modes() {
+        let mut rng = rand_chacha::ChaCha8Rng::from_seed([2; 32]);
+        let mut input = vec![0; crate::test::TEST_CASES_MAX];
+        paint_test_input(&mut input);
+        for &len in TEST_CASES {
+            let input = &input[..len];
+            for _ in 0..3 {
+                let hasher = OffsetHasher::new(len as u64);
+                write_shuffled(&hasher, input, &mut rng);
+                assert_eq!(hasher.finalize().unwrap(), crate::hash(input));
+
+                let hasher = OffsetHasher::with_mode(&Mode::keyed_hash(&TEST_KEY), len as u64);
+                write_shuffled(&hasher, input, &mut rng);
+                assert_eq!(
+                    hasher.finalize().unwrap(),
+                    crate::keyed_hash(&TEST_KEY, input)
+                );
+
+                let context = "BLAKE3 2019-12-27 16:29:52 test vectors context";
+                let hasher = OffsetHasher::with_mode(&Mode::derive_key(context), len as u64);
+                write_shuffled(&hasher, input, &mut rng);
+                let mut expected = [0; 100];
+                crate::Hasher::new_derive_key(context)
+                    .update(input)
+                    .finalize_xof()
+                    .fill(&mut expected);
+                let mut found = [0; 100];
+                hasher.finalize_xof().unwrap().fill(&mut found);
+                assert_eq!(found, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_offset_hasher_byte_at_a_time() {
+        let mut input = [0; 3 * CHUNK_LEN + 1];
+        paint_test_input(&mut input);
+        let hasher = OffsetHasher::new(input.len() as u64);
+        for i in (0..input.len()).rev() {
+            hasher.write(i as u64, &input[i..i + 1]).unwrap();
+        }
+        assert_eq!(hasher.finalize().unwrap(), crate::hash(&input));
+    }
+
+    #[test]
+    fn test_offset_hasher_finalize_during_write() {
+        // Finalize over and over while other threads are writing. Every
+        // attempt before the writes finish should fail cleanly, rather than
+        // panicking or returning the wrong hash.
+        let mut input = vec![0; 1 << 20];
+        paint_test_input(&mut input);
+        for &len in &[1000, CHUNK_LEN + 1, 1 << 20] {
+            let input = &input[..len];
+            let expected = crate::hash(input);
+
+            // Stop a write deterministically between claiming its range and
+            // storing its input. The range should count as missing.
+            let hasher = OffsetHasher::new(len as u64);
+            hasher.write(1, &input[1..]).unwrap();
+            hasher.state.lock().unwrap().reserve(0, 1).unwrap();
+            let err = hasher.finalize().unwrap_err();
+            assert_eq!(err.missing_ranges().len(), 1);
+            assert_eq!(err.missing_ranges()[0], 0..1);
+            hasher.write(0, &input[..1]).unwrap_err();
+
+            // Race real writes against finalize.
+            for _ in 0..10 {
+                let hasher = OffsetHasher::new(len as u64);
+                let done = std::sync::atomic::AtomicBool::new(false);
+                std::thread::scope(|scope| {
+                    let hasher = &hasher;
+                    let done = &done;
+                    scope.spawn(move || {
+                        let (first, second) = input.split_at(len / 2);
+                        hasher.write(len as u64 / 2, second).unwrap();
+                        hasher.write(0, first).unwrap();
+                        done.store(true, std::sync::atomic::Ordering::SeqCst);
+                    });
+                    loop {
+                        // Check done before finalizing, so that if it's set,
+                        // both writes have been published.
+                        let finished = done.load(std::sync::atomic::Ordering::SeqCst);
+                        match hasher.finalize() {
+                            Ok(hash) => {
+                                assert_eq!(hash, expected);
+                                break;
+                            }
+                            Err(err) => {
+                                assert!(!finished);
+                                assert!(!err.missing_ranges().is_empty());
+                                std::thread::yield_now();
+                            }
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    #[test]
+    fn test_offset_hasher_errors() {
+        let hasher = OffsetHasher::new(10_000);
+        hasher.write(1000, &[0; 2000]).unwrap();
+        hasher.write(5000, &[0; 1000]).unwrap();
+        hasher.write(3000, &[0; 1000]).unwrap();
+
+        let err = hasher.finalize().unwrap_err();
+        assert_eq!(err.missing_ranges(), &[0..1000, 4000..5000, 6000..10_000]);
+        assert_eq!(
+            err.to_string(),
+            "missing input: 0..1000 4000..5000 6000..10000",
+        );
+
+        // Overlaps on either side, or in the middle, are rejected, and don't
+        // change the state.
+        let overlap = hasher.write(900, &[0; 101]).unwrap_err();
+        assert!(overlap.missing_ranges().is_empty());
+        hasher.write(3999, &[0; 2]).unwrap_err();
+        hasher.write(0, &[0; 10_000]).unwrap_err();
+        hasher.write(5500, &[]).unwrap();
+        hasher.write(9000, &[0; 1001]).unwrap_err();
+        hasher.write(u64::MAX, &[0; 1]).unwrap_err();
+        assert_eq!(
+            hasher.finalize().unwrap_err().missing_ranges(),
+            &[0..1000, 4000..5000, 6000..10_000],
+        );
+
+        hasher.write(0, &[0; 1000]).unwrap();
+        hasher.write(4000, &[0; 1000]).unwrap();
+        hasher.write(6000, &[0; 4000]).unwrap();
+        assert_eq!(hasher.finalize().unwrap(), crate::hash(&[0; 10_000]));
+    }
+}
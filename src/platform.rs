@@ -166,6 +166,50 @@ impl Platform {
         }
     }
 
+    // Fill `out` with consecutive XOF output blocks, starting at `counter`.
+    // Unlike the blocks of a chunk, output blocks are independent of each
+    // other, so the SIMD implementations compute several at once.
+    pub fn xof_many(
+        &self,
+        cv: &CVWords,
+        block: &[u8; BLOCK_LEN],
+        block_len: u8,
+        counter: u64,
+        flags: u8,
+        out: &mut [u8],
+    ) {
+        debug_assert_eq!(0, out.len() % BLOCK_LEN, "whole blocks only");
+        match self {
+            Platform::Portable => portable::xof_many(cv, block, block_len, counter, flags, out),
+            // Safe because detect() checked for platform support.
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Platform::SSE2 => unsafe {
+                crate::sse2::xof_many(cv, block, block_len, counter, flags, out)
+            },
+            // Safe because detect() checked for platform support.
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Platform::SSE41 => unsafe {
+                crate::sse41::xof_many(cv, block, block_len, counter, flags, out)
+            },
+            // Safe because detect() checked for platform support.
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Platform::AVX2 => unsafe {
+                crate::avx2::xof_many(cv, block, block_len, counter, flags, out)
+            },
+            // Safe because detect() checked for platform support.
+            #[cfg(blake3_avx512_ffi)]
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Platform::AVX512 => unsafe {
+                crate::avx512::xof_many(cv, block, block_len, counter, flags, out)
+            },
+            // Assumed to be safe if the "neon" feature is on.
+            #[cfg(blake3_neon)]
+            Platform::NEON => unsafe {
+                crate::neon::xof_many(cv, block, block_len, counter, flags, out)
+            },
+        }
+    }
+
     // IMPLEMENTATION NOTE
     // ===================
     // hash_many() applies two optimizations. The critically important
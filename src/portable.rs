@@ -120,6 +120,21 @@ pub fn compress_xof(
     crate::platform::le_bytes_from_words_64(&state)
 }
 
+pub fn xof_many(
+    cv: &CVWords,
+    block: &[u8; BLOCK_LEN],
+    block_len: u8,
+    mut counter: u64,
+    flags: u8,
+    out: &mut [u8],
+) {
+    debug_assert_eq!(0, out.len() % BLOCK_LEN, "whole blocks only");
+    for out_block in out.chunks_exact_mut(BLOCK_LEN) {
+        out_block.copy_from_slice(&compress_xof(cv, block, block_len, counter, flags));
+        counter += 1;
+    }
+}
+
 pub fn hash1<const N: usize>(
     input: &[u8; N],
     key: &CVWords,
@@ -195,4 +210,10 @@ pub mod test {
     fn test_hash_many() {
         crate::test::test_hash_many_fn(hash_many, hash_many);
     }
+
+    // Ditto.
+    #[test]
+    fn test_xof_many() {
+        crate::test::test_xof_many_fn(xof_many);
+    }
 }
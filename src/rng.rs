@@ -0,0 +1,218 @@
+//! `Blake3Rng`, a deterministic random number generator backed by the XOF.
+
+use crate::{Hasher, OutputReader, BLOCK_LEN, KEY_LEN};
+use core::fmt;
+use rand_core::{CryptoRng, Error, RngCore, SeedableRng};
+
+/// A deterministic, cryptographically secure random number generator, which
+/// implements the [`rand_core`] traits on top of an [`OutputReader`].
+///
+/// The seed is a 32-byte key, and the generated stream is the extended output
+/// of [`Hasher::new_keyed`] with no input. Any other [`OutputReader`] can be
+/// used as the source of the stream too, for example one from
+/// [`Hasher::new_derive_key`], via `From<OutputReader>`.
+///
+/// The generator buffers one 64-byte block of output, so that
+/// [`next_u32`](RngCore::next_u32) and [`next_u64`](RngCore::next_u64) don't
+/// compress a whole block for each call. Large calls to
+/// [`fill_bytes`](RngCore::fill_bytes) write directly into the caller's
+/// buffer. The bytes generated are the same however the calls are mixed:
+/// integers are read from the stream in little-endian order.
+///
+/// [`set_position`](Blake3Rng::set_position) jumps to any offset in the
+/// stream, so that independent streams can be generated from the same seed
+/// without generating everything before them.
+///
+/// This type is gated by the `rand_core` Cargo feature, which is disabled by
+/// default.
+///
+/// # Example
+///
+/// ```
+/// use blake3::Blake3Rng;
+/// use rand_core::{RngCore, SeedableRng};
+///
+/// let mut rng = Blake3Rng::from_seed([42; 32]);
+/// let x = rng.next_u64();
+///
+/// // The stream is the same as the keyed XOF with no input.
+/// let mut expected = [0; 8];
+/// blake3::Hasher::new_keyed(&[42; 32]).finalize_xof().fill(&mut expected);
+/// assert_eq!(x, u64::from_le_bytes(expected));
+///
+/// // Jump to an independent stream a gigabyte in.
+/// rng.set_position(1 << 30);
+/// let y = rng.next_u64();
+/// ```
+#[derive(Clone)]
+pub struct Blake3Rng {
+    // Always positioned at a block boundary, just past the buffered block.
+    reader: OutputReader,
+    buf: [u8; BLOCK_LEN],
+    // The number of bytes of buf already used. BLOCK_LEN means it's empty.
+    buf_pos: usize,
+}
+
+impl Blake3Rng {
+    /// Return the current position in the output stream, the number of bytes
+    /// generated so far. A new `Blake3Rng` starts at 0.
+    pub fn position(&self) -> u64 {
+        self.reader.position() - (BLOCK_LEN - self.buf_pos) as u64
+    }
+
+    /// Seek to a new position in the output stream.
+    ///
+    /// As with [`OutputReader`], the maximum output size is
+    /// 2<sup>64</sup>-1 bytes, and the behavior past that point is
+    /// unspecified.
+    pub fn set_position(&mut self, position: u64) {
+        let offset = (position % BLOCK_LEN as u64) as usize;
+        self.reader.set_position(position - offset as u64);
+        self.buf_pos = BLOCK_LEN;
+        if offset > 0 {
+            self.refill();
+            self.buf_pos = offset;
+        }
+    }
+
+    fn refill(&mut self) {
+        self.reader.fill(&mut self.buf);
+        self.buf_pos = 0;
+    }
+
+    // Copy as much as possible from the buffer, and return the rest of dest.
+    fn take_buffered<'a>(&mut self, dest: &'a mut [u8]) -> &'a mut [u8] {
+        let available = &self.buf[self.buf_pos..];
+        let take = core::cmp::min(available.len(), dest.len());
+        dest[..take].copy_from_slice(&available[..take]);
+        self.buf_pos += take;
+        &mut dest[take..]
+    }
+}
+
+impl From<OutputReader> for Blake3Rng {
+    /// Generate the stream of `reader`, starting at its current position.
+    fn from(reader: OutputReader) -> Self {
+        let mut rng = Self {
+            reader,
+            buf: [0; BLOCK_LEN],
+            buf_pos: BLOCK_LEN,
+        };
+        let position = rng.reader.position();
+        rng.set_position(position);
+        rng
+    }
+}
+
+impl RngCore for Blake3Rng {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        self.fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        let mut dest = self.take_buffered(dest);
+        if dest.is_empty() {
+            return;
+        }
+        // The buffer is empty and the reader is at a block boundary. Write
+        // whole blocks directly, and buffer the block that's left over.
+        let whole_len = dest.len() - dest.len() % BLOCK_LEN;
+        let (whole, rest) = dest.split_at_mut(whole_len);
+        self.reader.fill(whole);
+        dest = rest;
+        if !dest.is_empty() {
+            self.refill();
+            self.take_buffered(dest);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for Blake3Rng {}
+
+impl SeedableRng for Blake3Rng {
+    type Seed = [u8; KEY_LEN];
+
+    /// Generate the extended output of [`Hasher::new_keyed`] with `seed` as
+    /// the key and no input.
+    fn from_seed(seed: Self::Seed) -> Self {
+        Hasher::new_keyed(&seed).finalize_xof().into()
+    }
+}
+
+// Don't derive(Debug), because the state may be secret.
+impl fmt::Debug for Blake3Rng {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Blake3Rng")
+            .field("position", &self.position())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::TEST_KEY;
+
+    fn expected_stream<const N: usize>() -> [u8; N] {
+        let mut out = [0; N];
+        Hasher::new_keyed(&TEST_KEY).finalize_xof().fill(&mut out);
+        out
+    }
+
+    #[test]
+    fn test_mixed_calls() {
+        let expected = expected_stream::<10_000>();
+        let mut rng = Blake3Rng::from_seed(TEST_KEY);
+        let mut position = 0;
+        // Interleave integers with fills of every length around a block.
+        for fill_len in 0..3 * BLOCK_LEN {
+            let x = rng.next_u32();
+            assert_eq!(x.to_le_bytes(), expected[position..][..4]);
+            position += 4;
+            let mut buf = [0; 3 * BLOCK_LEN];
+            rng.fill_bytes(&mut buf[..fill_len]);
+            assert_eq!(buf[..fill_len], expected[position..][..fill_len]);
+            position += fill_len;
+            let y = rng.next_u64();
+            assert_eq!(y.to_le_bytes(), expected[position..][..8]);
+            position += 8;
+            assert_eq!(rng.position(), position as u64);
+            if position > 9_000 {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_set_position() {
+        let expected = expected_stream::<1000>();
+        let mut rng = Blake3Rng::from_seed(TEST_KEY);
+        for &position in &[0, 1, 63, 64, 65, 500, 999, 100, 0] {
+            rng.set_position(position as u64);
+            assert_eq!(rng.position(), position as u64);
+            let mut buf = [0; 1000];
+            rng.fill_bytes(&mut buf[position..]);
+            assert_eq!(buf[position..], expected[position..]);
+        }
+
+        // An OutputReader that's already been seeked starts where it is.
+        let mut reader = Hasher::new_keyed(&TEST_KEY).finalize_xof();
+        reader.set_position(77);
+        let mut rng = Blake3Rng::from(reader);
+        assert_eq!(rng.position(), 77);
+        assert_eq!(rng.next_u32().to_le_bytes(), expected[77..][..4]);
+    }
+}
@@ -382,6 +382,91 @@ pub unsafe fn hash8(
     storeu(h_vecs[7], out.as_mut_ptr().add(7 * 4 * DEGREE));
 }
 
+#[target_feature(enable = "avx2")]
+unsafe fn xof8(
+    cv: &CVWords,
+    block: &[u8; BLOCK_LEN],
+    block_len: u8,
+    counter: u64,
+    flags: u8,
+    out: &mut [u8; DEGREE * BLOCK_LEN],
+) {
+    // Every lane compresses the same block, so the message words are
+    // broadcast rather than transposed. Only the counter differs.
+    let block_words = crate::platform::words_from_le_bytes_64(block);
+    let mut msg_vecs = [set1(0); 16];
+    for i in 0..16 {
+        msg_vecs[i] = set1(block_words[i]);
+    }
+    let (counter_low_vec, counter_high_vec) = load_counters(counter, IncrementCounter::Yes);
+    let mut v = [
+        set1(cv[0]),
+        set1(cv[1]),
+        set1(cv[2]),
+        set1(cv[3]),
+        set1(cv[4]),
+        set1(cv[5]),
+        set1(cv[6]),
+        set1(cv[7]),
+        set1(IV[0]),
+        set1(IV[1]),
+        set1(IV[2]),
+        set1(IV[3]),
+        counter_low_vec,
+        counter_high_vec,
+        set1(block_len as u32),
+        set1(flags as u32),
+    ];
+    round(&mut v, &msg_vecs, 0);
+    round(&mut v, &msg_vecs, 1);
+    round(&mut v, &msg_vecs, 2);
+    round(&mut v, &msg_vecs, 3);
+    round(&mut v, &msg_vecs, 4);
+    round(&mut v, &msg_vecs, 5);
+    round(&mut v, &msg_vecs, 6);
+    for i in 0..8 {
+        v[i] = xor(v[i], v[i + 8]);
+        v[i + 8] = xor(v[i + 8], set1(cv[i]));
+    }
+    let halves = mut_array_refs!(&mut v, DEGREE, DEGREE);
+    transpose_vecs(halves.0);
+    transpose_vecs(halves.1);
+    // The first eight vecs now hold the first half of each lane's output
+    // block, and the second eight vecs hold the second half.
+    for i in 0..DEGREE {
+        storeu(v[i], out.as_mut_ptr().add(i * BLOCK_LEN));
+        storeu(
+            v[DEGREE + i],
+            out.as_mut_ptr().add(i * BLOCK_LEN + 4 * DEGREE),
+        );
+    }
+}
+
+#[target_feature(enable = "avx2")]
+pub unsafe fn xof_many(
+    cv: &CVWords,
+    block: &[u8; BLOCK_LEN],
+    block_len: u8,
+    mut counter: u64,
+    flags: u8,
+    mut out: &mut [u8],
+) {
+    debug_assert_eq!(0, out.len() % BLOCK_LEN, "whole blocks only");
+    while out.len() >= DEGREE * BLOCK_LEN {
+        xof8(
+            cv,
+            block,
+            block_len,
+            counter,
+            flags,
+            array_mut_ref!(out, 0, DEGREE * BLOCK_LEN),
+        );
+        counter += DEGREE as u64;
+        out = &mut out[DEGREE * BLOCK_LEN..];
+    }
+    crate::sse41::xof_many(cv, block, block_len, counter, flags, out);
+}
+
 #[target_feature(enable = "avx2")]
 pub unsafe fn hash_many<const N: usize>(
     mut inputs: &[&[u8; N]],
@@ -471,4 +556,12 @@ mod test {
         }
         crate::test::test_hash_many_fn(hash_many, hash_many);
     }
+
+    #[test]
+    fn test_xof_many() {
+        if !crate::platform::avx2_detected() {
+            return;
+        }
+        crate::test::test_xof_many_fn(xof_many);
+    }
 }
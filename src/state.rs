@@ -0,0 +1,243 @@
+//! The serialized format of a `Hasher`, for `Hasher::to_state_bytes` and
+//! `Hasher::from_state_bytes`.
+//!
+//! Version 1 of the format is laid out like this. All integers are
+//! little-endian.
+//!
+//! | offset | length | field                                             |
+//! |--------|--------|---------------------------------------------------|
+//! | 0      | 1      | format version, currently 1                       |
+//! | 1      | 1      | mode flags: 0, `KEYED_HASH`, `DERIVE_KEY_MATERIAL` |
+//! | 2      | 32     | key words                                         |
+//! | 34     | 8      | chunk counter                                     |
+//! | 42     | 32     | chaining value of the current chunk               |
+//! | 74     | 1      | blocks compressed in the current chunk            |
+//! | 75     | 1      | buffered bytes in the current chunk               |
+//! | 76     | 64     | buffer of the current chunk, zero-padded          |
+//! | 140    | 1      | number of CVs in the CV stack                     |
+//! | 141    | 32 * n | the CV stack, from bottom to top                  |
+//!
+//! The platform isn't part of the format. It's detected again when a state is
+//! loaded, so a state can move between machines.
+
+use crate::{
+    platform, ChunkState, Hasher, BLOCK_LEN, CHUNK_LEN, DERIVE_KEY_MATERIAL, IV, KEYED_HASH,
+    MAX_DEPTH, OUT_LEN,
+};
+use arrayref::{array_ref, mut_array_refs};
+use arrayvec::ArrayVec;
+use core::cmp;
+use core::fmt;
+
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 141;
+
+/// The maximum length of the output of
+/// [`Hasher::to_state_bytes`](crate::Hasher::to_state_bytes).
+pub const MAX_STATE_LEN: usize = HEADER_LEN + (MAX_DEPTH + 1) * OUT_LEN;
+
+pub(crate) fn encode(hasher: &Hasher) -> ArrayVec<u8, MAX_STATE_LEN> {
+    // Only a SubtreeHasher sets this, and it doesn't expose its Hasher.
+    debug_assert_eq!(hasher.initial_chunk_counter, 0);
+    let mut header = [0; HEADER_LEN];
+    {
+        let (version, flags, key, counter, cv, blocks_compressed, buf_len, buf, stack_len) =
+            mut_array_refs![&mut header, 1, 1, 32, 8, 32, 1, 1, BLOCK_LEN, 1];
+        version[0] = VERSION;
+        flags[0] = hasher.chunk_state.flags;
+        *key = platform::le_bytes_from_words_32(&hasher.key);
+        *counter = hasher.chunk_state.chunk_counter.to_le_bytes();
+        *cv = platform::le_bytes_from_words_32(&hasher.chunk_state.cv);
+        blocks_compressed[0] = hasher.chunk_state.blocks_compressed;
+        buf_len[0] = hasher.chunk_state.buf_len;
+        *buf = hasher.chunk_state.buf;
+        stack_len[0] = hasher.cv_stack.len() as u8;
+    }
+    let mut bytes = ArrayVec::new();
+    bytes.try_extend_from_slice(&header).unwrap();
+    for cv in &hasher.cv_stack {
+        bytes.try_extend_from_slice(cv).unwrap();
+    }
+    bytes
+}
+
+pub(crate) fn decode(bytes: &[u8]) -> Result<Hasher, StateError> {
+    use StateErrorInner::*;
+
+    if bytes.is_empty() {
+        return Err(StateError(InvalidLen(0)));
+    }
+    if bytes[0] != VERSION {
+        return Err(StateError(UnsupportedVersion(bytes[0])));
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err(StateError(InvalidLen(bytes.len())));
+    }
+    let flags = bytes[1];
+    let key = platform::words_from_le_bytes_32(array_ref!(bytes, 2, 32));
+    let chunk_counter = u64::from_le_bytes(*array_ref!(bytes, 34, 8));
+    let cv = platform::words_from_le_bytes_32(array_ref!(bytes, 42, 32));
+    let blocks_compressed = bytes[74];
+    let buf_len = bytes[75];
+    let buf = *array_ref!(bytes, 76, BLOCK_LEN);
+    let stack_len = bytes[140] as usize;
+    if bytes.len() != HEADER_LEN + stack_len * OUT_LEN {
+        return Err(StateError(InvalidLen(bytes.len())));
+    }
+
+    // The mode flags must be one of the three modes, and the regular hash mode
+    // always uses the IV as its key.
+    if flags != 0 && flags != KEYED_HASH && flags != DERIVE_KEY_MATERIAL {
+        return Err(StateError(InvalidFlags(flags)));
+    }
+    if flags == 0 && key != *IV {
+        return Err(StateError(Inconsistent(
+            "wrong key for the regular hash mode",
+        )));
+    }
+
+    // The chunk state only compresses a block when more input arrives after
+    // it, so if any blocks have been compressed, the buffer can't be empty.
+    // The unused part of the buffer is always zero.
+    if buf_len as usize > BLOCK_LEN {
+        return Err(StateError(Inconsistent("chunk buffer too long")));
+    }
+    let chunk_len = BLOCK_LEN * blocks_compressed as usize + buf_len as usize;
+    if chunk_len > CHUNK_LEN || (blocks_compressed > 0 && buf_len == 0) {
+        return Err(StateError(Inconsistent("invalid chunk length")));
+    }
+    if buf[buf_len as usize..].iter().any(|&b| b != 0) {
+        return Err(StateError(Inconsistent(
+            "nonzero bytes past the end of the chunk buffer",
+        )));
+    }
+    if blocks_compressed == 0 && cv != key {
+        return Err(StateError(Inconsistent("chunk CV doesn't match the key")));
+    }
+
+    // The total input length is at most 2^64 - 1 bytes.
+    if chunk_counter >= 1 << MAX_DEPTH {
+        return Err(StateError(Inconsistent("chunk counter out of range")));
+    }
+
+    // The CV stack has one entry for each 1-bit in the chunk counter, as long
+    // as there's input in the chunk state. An empty chunk state with a nonzero
+    // counter isn't something update() leaves behind, but it's a valid state
+    // as far as finalize() is concerned, and in that case the top of the stack
+    // might not be merged yet. See Hasher::push_cv for the details of lazy
+    // merging.
+    let ones = chunk_counter.count_ones() as usize;
+    let stack_len_ok = if chunk_len > 0 {
+        stack_len == ones
+    } else if chunk_counter == 0 {
+        stack_len == 0
+    } else {
+        let max_unmerged = ones + chunk_counter.trailing_zeros() as usize;
+        stack_len >= cmp::max(ones, 2) && stack_len <= max_unmerged
+    };
+    if !stack_len_ok || stack_len > MAX_DEPTH + 1 {
+        return Err(StateError(Inconsistent(
+            "CV stack length doesn't match the input length",
+        )));
+    }
+
+    let platform = platform::Platform::detect();
+    let mut cv_stack = ArrayVec::new();
+    for stack_cv in bytes[HEADER_LEN..].chunks_exact(OUT_LEN) {
+        cv_stack.push(*array_ref!(stack_cv, 0, OUT_LEN));
+    }
+    Ok(Hasher {
+        key,
+        chunk_state: ChunkState {
+            cv,
+            chunk_counter,
+            buf,
+            buf_len,
+            blocks_compressed,
+            flags,
+            platform,
+        },
+        cv_stack,
+        initial_chunk_counter: 0,
+    })
+}
+
+/// The error type for [`Hasher::from_state_bytes`](crate::Hasher::from_state_bytes).
+///
+/// The `.to_string()` representation of this error currently describes what
+/// was wrong with the state. This is to help with logging and debugging, but
+/// it isn't a stable API detail, and it may change at any time.
+#[derive(Clone, Debug)]
+pub struct StateError(StateErrorInner);
+
+#[derive(Clone, Debug)]
+enum StateErrorInner {
+    UnsupportedVersion(u8),
+    InvalidLen(usize),
+    InvalidFlags(u8),
+    Inconsistent(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            StateErrorInner::UnsupportedVersion(version) => {
+                write!(f, "unsupported hasher state version: {}", version)
+            }
+            StateErrorInner::InvalidLen(len) => {
+                write!(f, "invalid hasher state length: {}", len)
+            }
+            StateErrorInner::InvalidFlags(flags) => {
+                write!(f, "invalid hasher state flags: 0x{:x}", flags)
+            }
+            StateErrorInner::Inconsistent(reason) => {
+                write!(f, "inconsistent hasher state: {}", reason)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for StateError {}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Hasher {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&encode(self))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Hasher {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_bytes(StateVisitor)
+    }
+}
+
+#[cfg(feature = "serde")]
+struct StateVisitor;
+
+#[cfg(feature = "serde")]
+impl<'de> serde::de::Visitor<'de> for StateVisitor {
+    type Value = Hasher;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a serialized BLAKE3 hasher state")
+    }
+
+    fn visit_bytes<E: serde::de::Error>(self, bytes: &[u8]) -> Result<Hasher, E> {
+        decode(bytes).map_err(E::custom)
+    }
+
+    // Self-describing formats like JSON represent bytes as a sequence.
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Hasher, A::Error> {
+        use serde::de::Error;
+        let mut bytes = ArrayVec::<u8, MAX_STATE_LEN>::new();
+        while let Some(byte) = seq.next_element()? {
+            if bytes.try_push(byte).is_err() {
+                return Err(A::Error::invalid_length(MAX_STATE_LEN + 1, &self));
+            }
+        }
+        decode(&bytes).map_err(A::Error::custom)
+    }
+}
@@ -0,0 +1,537 @@
+//! Hash aligned regions of a large input independently, and then merge them.
+//!
+//! BLAKE3 is a Merkle tree, so a large input can be split into pieces that
+//! are hashed on different threads, in different processes, or on different
+//! machines, as long as each piece lines up with a subtree of the full tree.
+//! [`SubtreeHasher`] hashes one such piece and returns its non-root
+//! [`ChainingValue`]. [`merge`] combines two adjacent chaining values into
+//! their parent, and [`merge_root`] and [`merge_root_xof`] combine the two
+//! children of the root node into the final hash.
+//!
+//! # Which regions are subtrees?
+//!
+//! The input is split into 1024-byte chunks, and the tree is always
+//! left-balanced: the left child of every parent node covers the largest
+//! power-of-two number of chunks that's strictly less than the total. That
+//! means that a region of the input is a subtree if and only if:
+//!
+//! - It starts at a multiple of 1024 bytes.
+//! - It isn't empty.
+//! - Its starting chunk index is a multiple of its number of chunks, rounded
+//!   up to a power of two.
+//!
+//! A subtree whose length isn't a power-of-two number of whole chunks can
+//! only appear at the end of the input. For example, 3072 bytes starting at
+//! offset 4096 is a valid subtree, if that's where the input ends, but 3072
+//! bytes starting at offset 2048 is not.
+//!
+//! Two adjacent subtrees can be merged if the left one is a power-of-two
+//! number of whole chunks, the right one is no longer than the left one, and
+//! together they form a valid subtree. The two children of the root are the
+//! left subtree starting at offset 0 and the right subtree that ends at the
+//! end of the input, so the root can only be merged from subtrees that
+//! satisfy those conditions too. Inputs of 1024 bytes or less are a single
+//! chunk with no parent nodes, and they should be hashed with a regular
+//! [`Hasher`](crate::Hasher) instead.
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), blake3::subtree::SubtreeError> {
+//! use blake3::subtree::{merge_root, Mode, SubtreeHasher};
+//!
+//! let input = vec![0xab; 10_000];
+//! let mode = Mode::hash();
+//!
+//! // The left subtree is 8 chunks starting at offset 0, and the right
+//! // subtree is the rest of the input. These could be hashed in parallel.
+//! let mut left = SubtreeHasher::new(&mode, 0)?;
+//! left.update(&input[..8192]);
+//! let left_cv = left.finalize()?;
+//! let mut right = SubtreeHasher::new(&mode, 8192)?;
+//! right.update(&input[8192..]);
+//! let right_cv = right.finalize()?;
+//!
+//! let hash = merge_root(&left_cv, &right_cv, &mode)?;
+//! assert_eq!(hash, blake3::hash(&input));
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    parent_node_output, platform, CVBytes, CVWords, Hash, Hasher, Output, OutputReader, CHUNK_LEN,
+    DERIVE_KEY_MATERIAL, IV, KEYED_HASH, KEY_LEN, OUT_LEN,
+};
+use core::fmt;
+
+/// The hashing mode of a subtree: regular hashing, keyed hashing, or key
+/// derivation. All the subtrees of an input must use the same mode, and the
+/// merge functions need it too.
+#[derive(Clone)]
+#[cfg_attr(feature = "zeroize", derive(zeroize::Zeroize))]
+pub struct Mode {
+    pub(crate) key: CVWords,
+    pub(crate) flags: u8,
+}
+
+impl Mode {
+    /// The mode for the regular hash function, [`hash`](crate::hash).
+    pub fn hash() -> Self {
+        Self { key: *IV, flags: 0 }
+    }
+
+    /// The mode for the keyed hash function, [`keyed_hash`](crate::keyed_hash).
+    pub fn keyed_hash(key: &[u8; KEY_LEN]) -> Self {
+        Self {
+            key: platform::words_from_le_bytes_32(key),
+            flags: KEYED_HASH,
+        }
+    }
+
+    /// The mode for the key derivation function, [`derive_key`](crate::derive_key).
+    /// The context string should be hardcoded, globally unique, and
+    /// application-specific.
+    pub fn derive_key(context: &str) -> Self {
+        Self {
+            key: crate::derive_key_context_key(context.as_bytes()),
+            flags: DERIVE_KEY_MATERIAL,
+        }
+    }
+}
+
+// Don't derive(Debug), because the key may be secret.
+impl fmt::Debug for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Mode").field("flags", &self.flags).finish()
+    }
+}
+
+/// The non-root chaining value of a subtree, along with the region of the
+/// input that it covers.
+///
+/// The region is used to check that merges are valid. To send a chaining
+/// value to another machine, send its bytes, offset, and length, and rebuild
+/// it on the other side with [`ChainingValue::new`].
+#[derive(Clone, Copy, Debug)]
+pub struct ChainingValue {
+    bytes: CVBytes,
+    input_offset: u64,
+    input_len: u64,
+}
+
+impl ChainingValue {
+    /// Reassemble a chaining value from its parts. This returns an error if
+    /// the region isn't a valid subtree.
+    pub fn new(
+        bytes: [u8; OUT_LEN],
+        input_offset: u64,
+        input_len: u64,
+    ) -> Result<Self, SubtreeError> {
+        check_subtree(input_offset, input_len)?;
+        Ok(Self {
+            bytes,
+            input_offset,
+            input_len,
+        })
+    }
+
+    /// The raw bytes of the chaining value.
+    pub fn as_bytes(&self) -> &[u8; OUT_LEN] {
+        &self.bytes
+    }
+
+    /// The offset in bytes where the subtree starts.
+    pub fn input_offset(&self) -> u64 {
+        self.input_offset
+    }
+
+    /// The number of input bytes in the subtree.
+    pub fn input_len(&self) -> u64 {
+        self.input_len
+    }
+
+    // A complete subtree is a power-of-two number of whole chunks. Any other
+    // subtree must be at the end of the input.
+    fn is_complete(&self) -> bool {
+        self.input_len.is_power_of_two() && self.input_len >= CHUNK_LEN as u64
+    }
+}
+
+/// An incremental hasher for a single subtree, starting at a given offset in
+/// the input.
+///
+/// This works like [`Hasher`](crate::Hasher), and large updates get the same
+/// SIMD and multithreading optimizations. However, the result is a non-root
+/// [`ChainingValue`], which needs to be merged with the rest of the tree.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "zeroize", derive(zeroize::Zeroize))]
+pub struct SubtreeHasher {
+    inner: Hasher,
+}
+
+impl SubtreeHasher {
+    /// Construct a new `SubtreeHasher` for a subtree starting at
+    /// `input_offset`. This returns an error if the offset isn't a multiple of
+    /// the 1024-byte chunk length.
+    pub fn new(mode: &Mode, input_offset: u64) -> Result<Self, SubtreeError> {
+        check_offset(input_offset)?;
+        let mut inner = Hasher::new_internal(&mode.key, mode.flags);
+        let chunk_counter = input_offset / CHUNK_LEN as u64;
+        inner.chunk_state.chunk_counter = chunk_counter;
+        inner.initial_chunk_counter = chunk_counter;
+        Ok(Self { inner })
+    }
+
+    /// Add input bytes to the subtree. See [`Hasher::update`](crate::Hasher::update).
+    pub fn update(&mut self, input: &[u8]) -> &mut Self {
+        self.inner.update(input);
+        self
+    }
+
+    /// As [`update`](SubtreeHasher::update), but using Rayon-based
+    /// multithreading internally. See
+    /// [`Hasher::update_rayon`](crate::Hasher::update_rayon).
+    ///
+    /// This method is gated by the `rayon` Cargo feature, which is disabled
+    /// by default but enabled on [docs.rs](https://docs.rs).
+    #[cfg(feature = "rayon")]
+    pub fn update_rayon(&mut self, input: &[u8]) -> &mut Self {
+        self.inner.update_rayon(input);
+        self
+    }
+
+    /// Finalize the subtree and return its non-root chaining value. This
+    /// returns an error if the input so far can't form a subtree at this
+    /// offset. See the [module docs](self) for the rules.
+    ///
+    /// Like [`Hasher::finalize`](crate::Hasher::finalize), this method is
+    /// idempotent.
+    pub fn finalize(&self) -> Result<ChainingValue, SubtreeError> {
+        let input_offset = self.inner.initial_chunk_counter * CHUNK_LEN as u64;
+        ChainingValue::new(
+            self.inner.final_output().chaining_value(),
+            input_offset,
+            self.inner.count(),
+        )
+    }
+}
+
+// CHUNK_LEN is a power of two, so we can check alignment with a bitmask.
+fn check_offset(input_offset: u64) -> Result<(), SubtreeError> {
+    if input_offset & (CHUNK_LEN as u64 - 1) != 0 {
+        return Err(SubtreeError(SubtreeErrorInner::UnalignedOffset(
+            input_offset,
+        )));
+    }
+    Ok(())
+}
+
+fn check_subtree(input_offset: u64, input_len: u64) -> Result<(), SubtreeError> {
+    use SubtreeErrorInner::*;
+    check_offset(input_offset)?;
+    if input_len == 0 {
+        return Err(SubtreeError(Empty));
+    }
+    if input_offset.checked_add(input_len).is_none() {
+        return Err(SubtreeError(TooLong));
+    }
+    let start_chunk = input_offset / CHUNK_LEN as u64;
+    let num_chunks = (input_len - 1) / CHUNK_LEN as u64 + 1;
+    if start_chunk & (num_chunks.next_power_of_two() - 1) != 0 {
+        return Err(SubtreeError(Misaligned {
+            input_offset,
+            input_len,
+        }));
+    }
+    Ok(())
+}
+
+fn check_merge(left: &ChainingValue, right: &ChainingValue) -> Result<(), SubtreeError> {
+    use SubtreeErrorInner::*;
+    if left.input_offset.checked_add(left.input_len) != Some(right.input_offset) {
+        return Err(SubtreeError(NotAdjacent));
+    }
+    if !left.is_complete() {
+        return Err(SubtreeError(IncompleteLeft));
+    }
+    if right.input_len > left.input_len {
+        return Err(SubtreeError(RightTooLong));
+    }
+    check_subtree(left.input_offset, left.input_len + right.input_len)
+}
+
+/// Merge two adjacent subtrees into their parent, which is not the root.
+/// This returns an error if the two subtrees aren't the children of a valid
+/// parent node. See the [module docs](self) for the rules.
+///
+/// Both subtrees must have been hashed with the same `mode`.
+pub fn merge(
+    left: &ChainingValue,
+    right: &ChainingValue,
+    mode: &Mode,
+) -> Result<ChainingValue, SubtreeError> {
+    check_merge(left, right)?;
+    let output = parent_node_output(
+        &left.bytes,
+        &right.bytes,
+        &mode.key,
+        mode.flags,
+        platform::Platform::detect(),
+    );
+    Ok(ChainingValue {
+        bytes: output.chaining_value(),
+        input_offset: left.input_offset,
+        input_len: left.input_len + right.input_len,
+    })
+}
+
+/// Merge the two children of the root node, and return the hash of the whole
+/// input. The left subtree must start at offset 0, and the right subtree must
+/// be the end of the input. This returns an error if the two subtrees aren't
+/// the children of a valid root node.
+///
+/// Both subtrees must have been hashed with the same `mode`.
+pub fn merge_root(
+    left: &ChainingValue,
+    right: &ChainingValue,
+    mode: &Mode,
+) -> Result<Hash, SubtreeError> {
+    Ok(root_output(left, right, mode)?.root_hash())
+}
+
+/// As [`merge_root`], but return an [`OutputReader`] for extended output.
+pub fn merge_root_xof(
+    left: &ChainingValue,
+    right: &ChainingValue,
+    mode: &Mode,
+) -> Result<OutputReader, SubtreeError> {
+    Ok(OutputReader::new(root_output(left, right, mode)?))
+}
+
+fn root_output(
+    left: &ChainingValue,
+    right: &ChainingValue,
+    mode: &Mode,
+) -> Result<Output, SubtreeError> {
+    if left.input_offset != 0 {
+        return Err(SubtreeError(SubtreeErrorInner::NotRoot));
+    }
+    check_merge(left, right)?;
+    Ok(parent_node_output(
+        &left.bytes,
+        &right.bytes,
+        &mode.key,
+        mode.flags,
+        platform::Platform::detect(),
+    ))
+}
+
+/// The error type for the subtree functions.
+///
+/// The `.to_string()` representation of this error currently describes which
+/// rule a subtree or merge broke. This is to help with logging and debugging,
+/// but it isn't a stable API detail, and it may change at any time.
+#[derive(Clone, Debug)]
+pub struct SubtreeError(SubtreeErrorInner);
+
+#[derive(Clone, Debug)]
+enum SubtreeErrorInner {
+    UnalignedOffset(u64),
+    Empty,
+    TooLong,
+    Misaligned { input_offset: u64, input_len: u64 },
+    NotAdjacent,
+    IncompleteLeft,
+    RightTooLong,
+    NotRoot,
+}
+
+impl fmt::Display for SubtreeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            SubtreeErrorInner::UnalignedOffset(offset) => {
+                write!(
+                    f,
+                    "subtree offset {} is not a multiple of the chunk length",
+                    offset
+                )
+            }
+            SubtreeErrorInner::Empty => write!(f, "subtree is empty"),
+            SubtreeErrorInner::TooLong => write!(f, "subtree extends past 2^64 bytes"),
+            SubtreeErrorInner::Misaligned {
+                input_offset,
+                input_len,
+            } => write!(
+                f,
+                "a subtree of {} bytes cannot start at offset {}",
+                input_len, input_offset
+            ),
+            SubtreeErrorInner::NotAdjacent => write!(f, "subtrees are not adjacent"),
+            SubtreeErrorInner::IncompleteLeft => write!(
+                f,
+                "left subtree is not a power-of-two number of whole chunks"
+            ),
+            SubtreeErrorInner::RightTooLong => {
+                write!(f, "right subtree is longer than left subtree")
+            }
+            SubtreeErrorInner::NotRoot => write!(f, "left subtree does not start at offset 0"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for SubtreeError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{paint_test_input, TEST_KEY};
+
+    const CONTEXT: &str = "BLAKE3 2019-12-27 16:29:52 test vectors context";
+
+    // Hash an input as two subtrees merged at the root, with the right child
+    // split again if it's big enough.
+    fn check_root(mode: &Mode, input: &[u8], expected: Hash) {
+        let mut left_len = CHUNK_LEN;
+        while left_len * 2 < input.len() {
+            left_len *= 2;
+        }
+        let mut left = SubtreeHasher::new(mode, 0).unwrap();
+        left.update(&input[..left_len]);
+        let left_cv = left.finalize().unwrap();
+
+        let right_input = &input[left_len..];
+        let right_cv = if right_input.len() > CHUNK_LEN {
+            let mut right_left_len = CHUNK_LEN;
+            while right_left_len * 2 < right_input.len() {
+                right_left_len *= 2;
+            }
+            let mut right_left = SubtreeHasher::new(mode, left_len as u64).unwrap();
+            right_left.update(&right_input[..right_left_len]);
+            let mut right_right =
+                SubtreeHasher::new(mode, (left_len + right_left_len) as u64).unwrap();
+            right_right.update(&right_input[right_left_len..]);
+            merge(
+                &right_left.finalize().unwrap(),
+                &right_right.finalize().unwrap(),
+                mode,
+            )
+            .unwrap()
+        } else {
+            let mut right = SubtreeHasher::new(mode, left_len as u64).unwrap();
+            right.update(right_input);
+            right.finalize().unwrap()
+        };
+
+        assert_eq!(merge_root(&left_cv, &right_cv, mode).unwrap(), expected);
+        let mut xof_bytes = [0; 100];
+        merge_root_xof(&left_cv, &right_cv, mode)
+            .unwrap()
+            .fill(&mut xof_bytes);
+        assert_eq!(&xof_bytes[..32], expected.as_bytes());
+    }
+
+    #[test]
+    fn test_merge_all_modes() {
+        let mut input = [0; 20 * CHUNK_LEN];
+        paint_test_input(&mut input);
+        for &len in crate::test::TEST_CASES {
+            if len <= CHUNK_LEN || len > input.len() {
+                continue;
+            }
+            let input = &input[..len];
+            check_root(&Mode::hash(), input, crate::hash(input));
+            check_root(
+                &Mode::keyed_hash(&TEST_KEY),
+                input,
+                crate::keyed_hash(&TEST_KEY, input),
+            );
+            let mut derive_key_hasher = crate::Hasher::new_derive_key(CONTEXT);
+            derive_key_hasher.update(input);
+            check_root(
+                &Mode::derive_key(CONTEXT),
+                input,
+                derive_key_hasher.finalize(),
+            );
+        }
+    }
+
+    #[test]
+    fn test_subtree_matches_guts() {
+        // A subtree of 4 chunks at chunk index 4 is the parent of two parents
+        // of two chunks each.
+        let mut input = [0; 4 * CHUNK_LEN];
+        paint_test_input(&mut input);
+        let mut subtree = SubtreeHasher::new(&Mode::hash(), 4 * CHUNK_LEN as u64).unwrap();
+        // Feed the input in uneven pieces to exercise the incremental path.
+        subtree.update(&input[..1000]);
+        subtree.update(&input[1000..3000]);
+        subtree.update(&input[3000..]);
+        let chunk_cv = |i: usize| {
+            crate::guts::ChunkState::new(4 + i as u64)
+                .update(&input[i * CHUNK_LEN..][..CHUNK_LEN])
+                .finalize(false)
+        };
+        let chunk_cvs = [chunk_cv(0), chunk_cv(1), chunk_cv(2), chunk_cv(3)];
+        let left = crate::guts::parent_cv(&chunk_cvs[0], &chunk_cvs[1], false);
+        let right = crate::guts::parent_cv(&chunk_cvs[2], &chunk_cvs[3], false);
+        let expected = crate::guts::parent_cv(&left, &right, false);
+        assert_eq!(subtree.finalize().unwrap().as_bytes(), expected.as_bytes());
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn test_update_rayon() {
+        let mut input = [0; 64 * CHUNK_LEN];
+        paint_test_input(&mut input);
+        let mode = Mode::keyed_hash(&TEST_KEY);
+        let mut serial = SubtreeHasher::new(&mode, 64 * CHUNK_LEN as u64).unwrap();
+        serial.update(&input);
+        let mut parallel = SubtreeHasher::new(&mode, 64 * CHUNK_LEN as u64).unwrap();
+        parallel.update_rayon(&input);
+        assert_eq!(
+            serial.finalize().unwrap().as_bytes(),
+            parallel.finalize().unwrap().as_bytes(),
+        );
+    }
+
+    #[test]
+    fn test_invalid_subtrees() {
+        let mode = Mode::hash();
+        assert!(SubtreeHasher::new(&mode, 1).is_err());
+        assert!(SubtreeHasher::new(&mode, CHUNK_LEN as u64 + 1).is_err());
+
+        // Empty.
+        let subtree = SubtreeHasher::new(&mode, 0).unwrap();
+        assert!(subtree.finalize().is_err());
+
+        // Three chunks can't start at chunk index 2, but two can, and so can
+        // one and a half.
+        let mut subtree = SubtreeHasher::new(&mode, 2 * CHUNK_LEN as u64).unwrap();
+        subtree.update(&[0; 3 * CHUNK_LEN]);
+        assert!(subtree.finalize().is_err());
+        assert!(ChainingValue::new([0; 32], 2 * CHUNK_LEN as u64, 2 * CHUNK_LEN as u64).is_ok());
+        assert!(ChainingValue::new([0; 32], 2 * CHUNK_LEN as u64, 1536).is_ok());
+        assert!(ChainingValue::new([0; 32], 2 * CHUNK_LEN as u64, 2049).is_err());
+        assert!(ChainingValue::new([0; 32], u64::MAX - 1023, 1025).is_err());
+
+        let cv = |offset: usize, len: usize| {
+            ChainingValue::new([0; 32], offset as u64, len as u64).unwrap()
+        };
+        // Fine.
+        merge(&cv(0, 1024), &cv(1024, 1024), &mode).unwrap();
+        merge(&cv(16384, 8192), &cv(24576, 1), &mode).unwrap();
+        merge_root(&cv(0, 2048), &cv(2048, 2000), &mode).unwrap();
+        // Not adjacent.
+        assert!(merge(&cv(0, 1024), &cv(2048, 1024), &mode).is_err());
+        // Left is incomplete.
+        assert!(merge(&cv(0, 1000), &cv(1024, 1024), &mode).is_err());
+        assert!(merge(&cv(0, 2048), &cv(2048, 1024), &mode).is_ok());
+        // Right is longer than left.
+        assert!(merge(&cv(1024, 1024), &cv(2048, 2048), &mode).is_err());
+        // The parent would be misaligned.
+        assert!(merge(&cv(1024, 1024), &cv(2048, 1024), &mode).is_err());
+        // The root must start at 0.
+        assert!(merge_root(&cv(16384, 8192), &cv(24576, 1), &mode).is_err());
+    }
+}
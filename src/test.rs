@@ -95,6 +95,58 @@ pub fn test_compress_fn(compress_in_place_fn: CompressInPlaceFn, compress_xof_fn
     assert_eq!(&portable_out[..], &test_xof[..]);
 }
 
+type XofManyFn = unsafe fn(
+    cv: &CVWords,
+    block: &[u8; BLOCK_LEN],
+    block_len: u8,
+    counter: u64,
+    flags: u8,
+    out: &mut [u8],
+);
+
+// A shared helper function for platform-specific tests.
+pub fn test_xof_many_fn(xof_many_fn: XofManyFn) {
+    let block_len: u8 = 61;
+    let mut block = [0; BLOCK_LEN];
+    paint_test_input(&mut block[..block_len as usize]);
+    let flags = crate::CHUNK_END | crate::ROOT | crate::KEYED_HASH;
+    // The same initial counters as test_hash_many_fn, for the same reasons.
+    let initial_counters = [0, u32::MAX as u64, i32::MAX as u64];
+    for counter in initial_counters {
+        // Enough blocks to cover every SIMD degree plus a remainder.
+        const MAX_BLOCKS: usize = 2 * 16 + 1;
+        let mut portable_out = [0; MAX_BLOCKS * BLOCK_LEN];
+        for (i, out_block) in portable_out.chunks_exact_mut(BLOCK_LEN).enumerate() {
+            out_block.copy_from_slice(&crate::portable::compress_xof(
+                &TEST_KEY_WORDS,
+                &block,
+                block_len,
+                counter + i as u64,
+                flags,
+            ));
+        }
+        for num_blocks in 0..=MAX_BLOCKS {
+            let mut test_out = [0; MAX_BLOCKS * BLOCK_LEN];
+            unsafe {
+                xof_many_fn(
+                    &TEST_KEY_WORDS,
+                    &block,
+                    block_len,
+                    counter,
+                    flags,
+                    &mut test_out[..num_blocks * BLOCK_LEN],
+                );
+            }
+            assert_eq!(
+                &portable_out[..num_blocks * BLOCK_LEN],
+                &test_out[..num_blocks * BLOCK_LEN],
+            );
+            // Nothing past the end should be written.
+            assert!(test_out[num_blocks * BLOCK_LEN..].iter().all(|&b| b == 0));
+        }
+    }
+}
+
 type HashManyFn<A> = unsafe fn(
     inputs: &[&A],
     key: &CVWords,
@@ -306,6 +358,14 @@ fn test_compare_reference_impl() {
                 assert_eq!(hasher.finalize(), *array_ref!(expected_out, 0, 32));
                 assert_eq!(hasher.finalize(), test_out);
             }
+            // incremental (threads)
+            #[cfg(feature = "std")]
+            {
+                let mut hasher = crate::Hasher::new();
+                hasher.update_with_join::<crate::join::ThreadJoin>(input);
+                assert_eq!(hasher.finalize(), *array_ref!(expected_out, 0, 32));
+                assert_eq!(hasher.finalize(), test_out);
+            }
             // xof
             let mut extended = [0; OUT];
             hasher.finalize_xof().fill(&mut extended);
@@ -335,6 +395,14 @@ fn test_compare_reference_impl() {
                 assert_eq!(hasher.finalize(), *array_ref!(expected_out, 0, 32));
                 assert_eq!(hasher.finalize(), test_out);
             }
+            // incremental (threads)
+            #[cfg(feature = "std")]
+            {
+                let mut hasher = crate::Hasher::new_keyed(&TEST_KEY);
+                hasher.update_with_join::<crate::join::ThreadJoin>(input);
+                assert_eq!(hasher.finalize(), *array_ref!(expected_out, 0, 32));
+                assert_eq!(hasher.finalize(), test_out);
+            }
             // xof
             let mut extended = [0; OUT];
             hasher.finalize_xof().fill(&mut extended);
@@ -365,14 +433,61 @@ fn test_compare_reference_impl() {
                 assert_eq!(hasher.finalize(), *array_ref!(expected_out, 0, 32));
                 assert_eq!(hasher.finalize(), *array_ref!(test_out, 0, 32));
             }
+            // incremental (threads)
+            #[cfg(feature = "std")]
+            {
+                let mut hasher = crate::Hasher::new_derive_key(context);
+                hasher.update_with_join::<crate::join::ThreadJoin>(input);
+                assert_eq!(hasher.finalize(), *array_ref!(expected_out, 0, 32));
+                assert_eq!(hasher.finalize(), *array_ref!(test_out, 0, 32));
+            }
             // xof
             let mut extended = [0; OUT];
             hasher.finalize_xof().fill(&mut extended);
             assert_eq!(extended, expected_out);
+            // all at once, xof
+            let mut extended = [0; OUT];
+            crate::derive_key_into(context, input, &mut extended);
+            assert_eq!(extended, expected_out);
+            let mut extended = [0; OUT];
+            crate::derive_key_xof(context, input).fill(&mut extended);
+            assert_eq!(extended, expected_out);
+            // raw context
+            assert_eq!(crate::derive_key_raw(context.as_bytes(), input), test_out);
+            let mut hasher = crate::Hasher::new_derive_key_raw(context.as_bytes());
+            hasher.update(input);
+            assert_eq!(hasher.finalize(), *array_ref!(test_out, 0, 32));
         }
     }
 }
 
+#[test]
+fn test_derive_key_raw_non_utf8() {
+    // This output comes from blake3_hasher_init_derive_key_raw in the C
+    // implementation.
+    let expected = "86478c6ec4e0b0f95b880dd1e91f1a6037b245d12689144f03d3f15913d6f43d\
+                    3dfafda71c467d137728067171f1e3db498a7c45ea172fbf91dd521eadaed1ba\
+                    19cc27ce4e95a2082f199f09a8a6dc0e2ec7a2d7d28a33fd3d3b75c513d14eda";
+    let context = b"\xff\x00\xfectx\x80";
+    let material = b"key material";
+    assert_eq!(
+        hex::encode(crate::derive_key_raw(context, material)),
+        expected[..64]
+    );
+    let mut out = [0; 96];
+    crate::Hasher::new_derive_key_raw(context)
+        .update(material)
+        .finalize_xof()
+        .fill(&mut out);
+    assert_eq!(hex::encode(out), expected);
+    let mut out = [0; 96];
+    crate::derive_key_raw_into(context, material, &mut out);
+    assert_eq!(hex::encode(out), expected);
+    let mut out = [0; 96];
+    crate::derive_key_raw_xof(context, material).fill(&mut out);
+    assert_eq!(hex::encode(out), expected);
+}
+
 fn reference_hash(input: &[u8]) -> crate::Hash {
     let mut hasher = reference_impl::Hasher::new();
     hasher.update(input);
@@ -449,6 +564,56 @@ fn test_fuzz_hasher() {
     }
 }
 
+#[test]
+fn test_xof_fill_matches_single_blocks() {
+    let mut hasher = crate::Hasher::new_keyed(&TEST_KEY);
+    hasher.update(b"foo");
+    // A reference stream built one block at a time with compress_xof.
+    const LEN: usize = 40 * BLOCK_LEN;
+    let mut expected = [0; LEN];
+    let output = hasher.final_output();
+    for (i, block) in expected.chunks_exact_mut(BLOCK_LEN).enumerate() {
+        let mut output = output.clone();
+        output.counter = i as u64;
+        block.copy_from_slice(&output.root_output_block());
+    }
+
+    // Read at unaligned and aligned positions, in lengths that cover partial
+    // blocks at either end and more whole blocks than any SIMD degree.
+    let positions = [0, 1, 63, 64, 65, 100, 128, 1000];
+    let lengths = [0, 1, 63, 64, 65, 127, 128, 129, 1000, 17 * BLOCK_LEN + 3];
+    for &position in &positions {
+        for &len in &lengths {
+            let mut out = [0; LEN];
+            let mut reader = hasher.finalize_xof();
+            reader.set_position(position as u64);
+            reader.fill(&mut out[..len]);
+            assert_eq!(&expected[position..][..len], &out[..len]);
+            assert_eq!(reader.position(), (position + len) as u64);
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "rayon")]
+fn test_xof_fill_rayon() {
+    let mut hasher = crate::Hasher::new_keyed(&TEST_KEY);
+    hasher.update(b"foo");
+    // Long enough to split many times, and not a multiple of the block size.
+    let len = (1 << 20) + 7;
+    let mut expected = vec![0; 1000 + len];
+    hasher.finalize_xof().fill(&mut expected);
+    for &position in &[0, 1, 64, 1000] {
+        dbg!(position);
+        let mut out = vec![0; len];
+        let mut reader = hasher.finalize_xof();
+        reader.set_position(position as u64);
+        reader.fill_rayon(&mut out);
+        assert_eq!(&expected[position..][..len], &out[..]);
+        assert_eq!(reader.position(), (position + len) as u64);
+    }
+}
+
 #[test]
 fn test_xof_seek() {
     let mut out = [0; 533];
@@ -650,6 +815,7 @@ fn test_zeroize() {
         },
         key: [42; 8],
         cv_stack: [[42; 32]; { crate::MAX_DEPTH + 1 }].into(),
+        initial_chunk_counter: 42,
     };
     hasher.zeroize();
     assert_eq!(hasher.chunk_state.cv, [0; 8]);
@@ -661,6 +827,7 @@ fn test_zeroize() {
     assert!(matches!(hasher.chunk_state.platform, crate::Platform::Portable));
     assert_eq!(hasher.key, [0; 8]);
     assert_eq!(&*hasher.cv_stack, &[[0u8; 32]; 0]);
+    assert_eq!(hasher.initial_chunk_counter, 0);
 
 
     let mut output_reader = crate::OutputReader {
@@ -685,4 +852,540 @@ fn test_zeroize() {
     assert!(matches!(output_reader.inner.platform, crate::Platform::Portable));
     assert_eq!(output_reader.position_within_block, 0);
 
-}
\ No newline at end of file
+}
+#[test]
+#[cfg(feature = "mmap")]
+fn test_mmap() {
+    // This is a brief test, since update_mmap() is mostly a wrapper around
+    // update(). The interesting cases are the size thresholds where we fall
+    // back to regular reads.
+    let mut input = vec![0; 3 * 16 * 1024 + 1];
+    paint_test_input(&mut input);
+    for &len in &[0, 1, 16 * 1024 - 1, 16 * 1024, input.len()] {
+        dbg!(len);
+        let mut tempfile = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut tempfile, &input[..len]).unwrap();
+        std::io::Write::flush(&mut tempfile).unwrap();
+        let expected = crate::hash(&input[..len]);
+        assert_eq!(
+            expected,
+            crate::Hasher::new()
+                .update_mmap(tempfile.path())
+                .unwrap()
+                .finalize(),
+        );
+        #[cfg(feature = "rayon")]
+        assert_eq!(
+            expected,
+            crate::Hasher::new()
+                .update_mmap_rayon(tempfile.path())
+                .unwrap()
+                .finalize(),
+        );
+    }
+}
+
+#[test]
+#[cfg(feature = "mmap")]
+#[cfg(target_os = "linux")]
+fn test_mmap_virtual_file() {
+    // Virtual files like /proc/version report a size of 0, even though they
+    // have contents. Make sure we fall back to reading them normally.
+    let virtual_filepath = "/proc/version";
+    let mut mmap_hasher = crate::Hasher::new();
+    // We'll fail if this doesn't fall back to regular reads.
+    mmap_hasher.update_mmap(virtual_filepath).unwrap();
+    let read_contents = std::fs::read(virtual_filepath).unwrap();
+    assert!(!read_contents.is_empty());
+    assert_eq!(crate::hash(&read_contents), mmap_hasher.finalize());
+}
+
+#[test]
+#[cfg(feature = "mmap")]
+fn test_mmap_missing_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("missing");
+    let err = crate::Hasher::new().update_mmap(&missing).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+}
+
+// A reader that returns short reads of varying lengths, and that sometimes
+// returns ErrorKind::Interrupted, to exercise the retry logic in
+// update_reader() and update_reader_rayon(). After `fail_after` bytes, it
+// returns a different error.
+#[cfg(feature = "std")]
+struct StutteringReader<'a> {
+    input: &'a [u8],
+    position: usize,
+    reads: usize,
+    fail_after: Option<usize>,
+}
+
+#[cfg(feature = "std")]
+impl std::io::Read for StutteringReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reads += 1;
+        if self.reads % 3 == 2 {
+            return Err(std::io::ErrorKind::Interrupted.into());
+        }
+        if Some(self.position) == self.fail_after {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "stutter"));
+        }
+        let mut end = self.input.len();
+        if let Some(fail_after) = self.fail_after {
+            end = end.min(fail_after);
+        }
+        let want = buf.len().min(1 + self.reads * 997 % 70_000);
+        let take = want.min(end - self.position);
+        buf[..take].copy_from_slice(&self.input[self.position..][..take]);
+        self.position += take;
+        Ok(take)
+    }
+}
+
+#[test]
+fn test_update_zeros() {
+    static ZEROS: [u8; 40 * CHUNK_LEN] = [0; 40 * CHUNK_LEN];
+    let modes = [
+        crate::Hasher::new(),
+        crate::Hasher::new_keyed(&TEST_KEY),
+        crate::Hasher::new_derive_key("update_zeros test context"),
+    ];
+    for mode in &modes {
+        for &prefix_len in &[0, 1, CHUNK_LEN, 3 * CHUNK_LEN + 5, 16 * CHUNK_LEN] {
+            for &len in &[0, 1, CHUNK_LEN, 16 * CHUNK_LEN + 1, 40 * CHUNK_LEN] {
+                let mut prefix = [0; 16 * CHUNK_LEN];
+                paint_test_input(&mut prefix[..prefix_len]);
+                let mut expected = mode.clone();
+                expected.update(&prefix[..prefix_len]);
+                expected.update(&ZEROS[..len]);
+                expected.update(b"suffix");
+                let mut hasher = mode.clone();
+                hasher.update(&prefix[..prefix_len]);
+                hasher.update_zeros(len as u64);
+                hasher.update(b"suffix");
+                assert_eq!(expected.finalize(), hasher.finalize());
+            }
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_update_reader() {
+    let mut input = vec![0; 1_000_000];
+    paint_test_input(&mut input);
+    for &len in &[0, 1, CHUNK_LEN, 65536, 65536 + 1, input.len()] {
+        dbg!(len);
+        let expected = crate::hash(&input[..len]);
+        let reader = StutteringReader {
+            input: &input[..len],
+            position: 0,
+            reads: 0,
+            fail_after: None,
+        };
+        let mut hasher = crate::Hasher::new();
+        hasher.update_reader(reader).unwrap();
+        assert_eq!(expected, hasher.finalize());
+        assert_eq!(len as u64, hasher.count());
+    }
+}
+
+#[test]
+#[cfg(feature = "rayon")]
+fn test_update_reader_rayon() {
+    // Long enough to go through both buffers more than once, and not a
+    // multiple of the buffer size.
+    let mut input = vec![0; 5 * (1 << 20) / 2 + 7];
+    paint_test_input(&mut input);
+    for &len in &[0, 1, CHUNK_LEN + 1, 1 << 20, (1 << 20) + 1, input.len()] {
+        dbg!(len);
+        let expected = crate::keyed_hash(&TEST_KEY, &input[..len]);
+        let reader = StutteringReader {
+            input: &input[..len],
+            position: 0,
+            reads: 0,
+            fail_after: None,
+        };
+        let mut hasher = crate::Hasher::new_keyed(&TEST_KEY);
+        hasher.update_reader_rayon(reader).unwrap();
+        assert_eq!(expected, hasher.finalize());
+        assert_eq!(len as u64, hasher.count());
+    }
+}
+
+#[test]
+#[cfg(feature = "rayon")]
+fn test_update_rayon_with() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    let mut input = vec![0; (1 << 20) + 7];
+    paint_test_input(&mut input);
+    let expected = crate::keyed_hash(&TEST_KEY, &input);
+    // Start with a partial chunk, so that the steps are misaligned.
+    let cancel = AtomicBool::new(false);
+    let mut hasher = crate::Hasher::new_keyed(&TEST_KEY);
+    hasher.update(&input[..10]);
+    let mut reports = Vec::new();
+    hasher
+        .update_rayon_with(&input[10..], 100_000, &cancel, |done| reports.push(done))
+        .unwrap();
+    assert_eq!(expected, hasher.finalize());
+    // The step length rounds up to 128 KiB, and the first step ends at the
+    // first 128 KiB boundary of the whole input.
+    assert_eq!(reports[0], (1 << 17) - 10);
+    assert_eq!(reports[1], (2 << 17) - 10);
+    assert_eq!(*reports.last().unwrap(), input.len() - 10);
+    assert_eq!(reports.len(), 9);
+
+    // A huge step length is clamped rather than overflowing.
+    let mut hasher = crate::Hasher::new_keyed(&TEST_KEY);
+    let mut reports = Vec::new();
+    hasher
+        .update_rayon_with(&input, usize::MAX, &cancel, |done| reports.push(done))
+        .unwrap();
+    assert_eq!(expected, hasher.finalize());
+    assert_eq!(reports, [input.len()]);
+
+    // Cancel partway through, and then resume.
+    let mut hasher = crate::Hasher::new_keyed(&TEST_KEY);
+    let err = hasher
+        .update_rayon_with(&input, 1 << 16, &cancel, |done| {
+            if done >= 3 << 16 {
+                cancel.store(true, Ordering::Relaxed);
+            }
+        })
+        .unwrap_err();
+    assert_eq!(err.bytes_hashed(), 3 << 16);
+    assert_eq!(hasher.count(), 3 << 16);
+    assert_eq!(err.to_string(), "update cancelled after 196608 bytes");
+    // A cancelled update hashes nothing.
+    let err = hasher
+        .update_rayon_with(&input[3 << 16..], 1 << 16, &cancel, |_| panic!())
+        .unwrap_err();
+    assert_eq!(err.bytes_hashed(), 0);
+    cancel.store(false, Ordering::Relaxed);
+    hasher
+        .update_rayon_with(&input[3 << 16..], 1 << 16, &cancel, |_| {})
+        .unwrap();
+    assert_eq!(expected, hasher.finalize());
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_update_reader_error() {
+    let mut input = vec![0; 3 << 20];
+    paint_test_input(&mut input);
+    let fail_after = (2 << 20) + 42;
+    let reader = StutteringReader {
+        input: &input,
+        position: 0,
+        reads: 0,
+        fail_after: Some(fail_after),
+    };
+    let mut hasher = crate::Hasher::new();
+    let err = hasher.update_reader(reader).unwrap_err();
+    assert_eq!(err.to_string(), "stutter");
+    // Everything before the error gets hashed.
+    assert_eq!(hasher.finalize(), crate::hash(&input[..fail_after]));
+
+    #[cfg(feature = "rayon")]
+    {
+        let reader = StutteringReader {
+            input: &input,
+            position: 0,
+            reads: 0,
+            fail_after: Some(fail_after),
+        };
+        let mut hasher = crate::Hasher::new();
+        let err = hasher.update_reader_rayon(reader).unwrap_err();
+        assert_eq!(err.to_string(), "stutter");
+        assert_eq!(hasher.finalize(), crate::hash(&input[..fail_after]));
+    }
+}
+
+#[test]
+fn test_state_bytes_round_trip() {
+    let mut input = [0; 10 * CHUNK_LEN + 1];
+    paint_test_input(&mut input);
+    let modes = [
+        crate::Hasher::new(),
+        crate::Hasher::new_keyed(&TEST_KEY),
+        crate::Hasher::new_derive_key("BLAKE3 2019-12-27 16:29:52 test vectors context"),
+    ];
+    for mode in &modes {
+        let mut expected_hasher = mode.clone();
+        expected_hasher.update(&input);
+        let expected = expected_hasher.finalize();
+        for &split in TEST_CASES {
+            if split > input.len() {
+                continue;
+            }
+            let mut hasher = mode.clone();
+            hasher.update(&input[..split]);
+            let state = hasher.to_state_bytes();
+            assert!(state.len() <= crate::MAX_STATE_LEN);
+            let mut resumed = crate::Hasher::from_state_bytes(&state).unwrap();
+            assert_eq!(resumed.count(), split as u64);
+            assert_eq!(&resumed.to_state_bytes()[..], &state[..]);
+            assert_eq!(resumed.finalize(), hasher.finalize());
+            resumed.update(&input[split..]);
+            assert_eq!(resumed.finalize(), expected);
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_state_bytes_rejects_corruption() {
+    let mut input = [0; 3 * CHUNK_LEN + 100];
+    paint_test_input(&mut input);
+    let mut hasher = crate::Hasher::new();
+    hasher.update(&input);
+    let state = hasher.to_state_bytes();
+    crate::Hasher::from_state_bytes(&state).unwrap();
+
+    let check = |f: &dyn Fn(&mut Vec<u8>), expected_err: &str| {
+        let mut bad = state.to_vec();
+        f(&mut bad);
+        let err = crate::Hasher::from_state_bytes(&bad).unwrap_err();
+        assert_eq!(err.to_string(), expected_err);
+    };
+    check(&|s| s.clear(), "invalid hasher state length: 0");
+    check(&|s| s[0] = 2, "unsupported hasher state version: 2");
+    check(&|s| s.truncate(140), "invalid hasher state length: 140");
+    check(
+        &|s| {
+            s.pop();
+        },
+        "invalid hasher state length: 204",
+    );
+    check(
+        &|s| s[1] = crate::KEYED_HASH | crate::DERIVE_KEY_MATERIAL,
+        "invalid hasher state flags: 0x50",
+    );
+    check(&|s| s[1] = crate::ROOT, "invalid hasher state flags: 0x8");
+    check(
+        &|s| s[2] ^= 1,
+        "inconsistent hasher state: wrong key for the regular hash mode",
+    );
+    check(
+        &|s| s[75] = 65,
+        "inconsistent hasher state: chunk buffer too long",
+    );
+    check(
+        &|s| s[74] = 16,
+        "inconsistent hasher state: invalid chunk length",
+    );
+    check(
+        &|s| s[139] = 1,
+        "inconsistent hasher state: nonzero bytes past the end of the chunk buffer",
+    );
+    check(
+        &|s| s[74] = 0,
+        "inconsistent hasher state: chunk CV doesn't match the key",
+    );
+    check(
+        &|s| s[41] = 0x01,
+        "inconsistent hasher state: chunk counter out of range",
+    );
+    // Three chunks so far means two CVs in the stack. Adding or removing one
+    // (and fixing up the length) is inconsistent.
+    assert_eq!(state[140], 2);
+    check(
+        &|s| {
+            s[140] = 3;
+            s.extend_from_slice(&[0; 32]);
+        },
+        "inconsistent hasher state: CV stack length doesn't match the input length",
+    );
+    check(
+        &|s| {
+            s[140] = 1;
+            s.truncate(s.len() - 32);
+        },
+        "inconsistent hasher state: CV stack length doesn't match the input length",
+    );
+    check(
+        &|s| s[34] = 4,
+        "inconsistent hasher state: CV stack length doesn't match the input length",
+    );
+}
+
+#[test]
+#[cfg(all(feature = "std", feature = "serde"))]
+fn test_serde() {
+    let mut input = [0; 5 * CHUNK_LEN + 7];
+    paint_test_input(&mut input);
+    let mut hasher = crate::Hasher::new_keyed(&TEST_KEY);
+    hasher.update(&input[..3 * CHUNK_LEN + 1]);
+    let json = serde_json::to_string(&hasher).unwrap();
+    let mut resumed: crate::Hasher = serde_json::from_str(&json).unwrap();
+    resumed.update(&input[3 * CHUNK_LEN + 1..]);
+    assert_eq!(resumed.finalize(), crate::keyed_hash(&TEST_KEY, &input));
+
+    // Invalid states are rejected during deserialization too.
+    let bad_json = json.replacen("[1,16,", "[1,17,", 1);
+    assert_ne!(bad_json, json);
+    let err = serde_json::from_str::<crate::Hasher>(&bad_json).unwrap_err();
+    assert!(err.to_string().contains("invalid hasher state flags"));
+}
+
+#[test]
+fn test_derive_key_context() {
+    const CONTEXT: &str = "BLAKE3 2019-12-27 16:29:52 test vectors context";
+    const PRECOMPUTED: crate::DeriveKeyContext = crate::DeriveKeyContext::new(CONTEXT);
+    let material = b"key material";
+    let mut expected = [0; 100];
+    crate::derive_key_xof(CONTEXT, material).fill(&mut expected);
+    assert_eq!(PRECOMPUTED.derive_key(material), expected[..32]);
+    let mut out = [0; 100];
+    PRECOMPUTED.derive_key_xof(material).fill(&mut out);
+    assert_eq!(out, expected);
+    let mut out = [0; 100];
+    PRECOMPUTED
+        .hasher()
+        .update(material)
+        .finalize_xof()
+        .fill(&mut out);
+    assert_eq!(out, expected);
+    let materials: [&[u8]; 3] = [material, b"", &[0xff; 2000]];
+    let mut keys = [[0; 32]; 3];
+    PRECOMPUTED.derive_key_many(&materials, &mut keys);
+    for (material, key) in materials.iter().zip(&keys) {
+        assert_eq!(*key, crate::derive_key(CONTEXT, material));
+    }
+    #[cfg(feature = "rayon")]
+    {
+        let mut rayon_keys = [[0; 32]; 3];
+        PRECOMPUTED.derive_key_many_rayon(&materials, &mut rayon_keys);
+        assert_eq!(rayon_keys, keys);
+    }
+
+    // Contexts of every shape of tree, compared against the regular
+    // implementation of the first stage.
+    let mut context = [0; TEST_CASES_MAX];
+    paint_test_input(&mut context);
+    for &case in TEST_CASES {
+        let precomputed = crate::DeriveKeyContext::new_raw(&context[..case]);
+        assert_eq!(
+            precomputed.derive_key(material),
+            crate::derive_key_raw(&context[..case], material),
+            "case {}",
+            case,
+        );
+    }
+}
+
+#[test]
+fn test_key_tree() {
+    let mut root_key = [0; 32];
+    for (i, b) in root_key.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    let root = crate::KeyTree::new(&root_key);
+
+    // Check the encoding of one derivation step against the reference
+    // implementation.
+    let label = b"tenant 7";
+    let mut reference = reference_impl::Hasher::new_derive_key(crate::KEY_TREE_CONTEXT);
+    reference.update(&root_key);
+    reference.update(&(label.len() as u64).to_le_bytes());
+    reference.update(label);
+    let mut expected = [0; 32];
+    reference.finalize(&mut expected);
+    assert_eq!(root.child(label).key().as_bytes(), &expected);
+
+    // The vectors that other implementations test against. The test_vectors
+    // crate checks that this file is up to date.
+    let vectors: serde_json::Value =
+        serde_json::from_str(include_str!("../test_vectors/key_tree_vectors.json")).unwrap();
+    assert_eq!(vectors["context_string"], crate::KEY_TREE_CONTEXT);
+    assert_eq!(vectors["root_key"], hex::encode(root_key));
+    for case in vectors["cases"].as_array().unwrap() {
+        let labels = case["labels"].as_array().unwrap();
+        let labels = labels
+            .iter()
+            .map(|label| hex::decode(label.as_str().unwrap()).unwrap());
+        let expected = case["key"].as_str().unwrap();
+        let mut node = root.clone();
+        for label in labels.clone() {
+            node = node.child(&label);
+        }
+        assert_eq!(hex::encode(node.key().as_bytes()), expected);
+        #[cfg(feature = "std")]
+        {
+            let path: crate::DerivationPath = labels.clone().collect();
+            assert_eq!(path.len(), labels.len());
+            assert_eq!(hex::encode(root.derive(&path).key().as_bytes()), expected);
+        }
+    }
+
+    // Splitting or joining labels changes the key.
+    let ab = root.child(b"a").child(b"b").key();
+    assert_ne!(ab, root.child(b"ab").key());
+    assert_ne!(ab, root.child(b"a\x00b").key());
+    assert_ne!(ab, root.child(b"a").key());
+
+    // An exported intermediate key derives the same descendants.
+    let exported = crate::KeyTree::new(root.child(b"tenant 7").key().as_bytes());
+    assert_eq!(
+        exported.child(b"billing").key(),
+        root.child(b"tenant 7").child(b"billing").key(),
+    );
+    #[cfg(feature = "std")]
+    assert_eq!(format!("{:?}", root), "KeyTree { .. }");
+}
+
+#[test]
+fn test_key() {
+    let hex = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    let mut bytes = [0; crate::KEY_LEN];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    let key = crate::Key::from_hex(hex).unwrap();
+    assert_eq!(key.as_bytes(), &bytes);
+    assert_eq!(key.to_hex().as_str(), hex);
+    assert_eq!(hex.parse::<crate::Key>().unwrap(), key);
+    assert_eq!(crate::Key::from(bytes), key);
+    assert!(crate::Key::from_hex(&hex[1..]).is_err());
+
+    // The raw-key functions keep their signatures.
+    let _: fn(&[u8; crate::KEY_LEN], &[u8]) -> crate::Hash = crate::keyed_hash;
+    let _: fn(&[u8; crate::KEY_LEN]) -> crate::Hasher = crate::Hasher::new_keyed;
+
+    // The Key functions match the raw-key functions.
+    let input = b"some input";
+    assert_eq!(
+        crate::keyed_hash_with(&key, input),
+        crate::keyed_hash(&bytes, input),
+    );
+    assert_eq!(
+        crate::Hasher::new_keyed_with(&key).update(input).finalize(),
+        crate::keyed_hash(&bytes, input),
+    );
+
+    let context = "BLAKE3 2019-12-27 16:29:52 test vectors context";
+    let derived = crate::Key::derive(context, input);
+    assert_eq!(derived.as_bytes(), &crate::derive_key(context, input));
+    assert_ne!(derived, key);
+
+    #[cfg(feature = "std")]
+    assert_eq!(format!("{:?}", key), "Key { .. }");
+
+    #[cfg(feature = "getrandom")]
+    {
+        let random1 = crate::Key::generate().unwrap();
+        let random2 = crate::Key::generate().unwrap();
+        assert_ne!(random1, random2);
+    }
+
+    #[cfg(feature = "zeroize")]
+    {
+        use zeroize::Zeroize;
+        let mut key = key.clone();
+        key.zeroize();
+        assert_eq!(key.as_bytes(), &[0; crate::KEY_LEN]);
+    }
+}
@@ -0,0 +1,4 @@
+fn main() {
+    // The trailing newline is included.
+    print!("{}", test_vectors::generate_key_tree_json());
+}
@@ -139,6 +139,80 @@ pub fn parse_test_cases() -> Cases {
     serde_json::from_str(&json).expect("failed to parse test_vectors.json")
 }
 
+// Paths from the root of a KeyTree. Each one extends the one before it, apart
+// from the empty label and the label that isn't UTF-8.
+pub const KEY_TREE_PATHS: &[&[&[u8]]] = &[
+    &[b""],
+    &[b"\xff\x00\x80"],
+    &[b"tenant 7"],
+    &[b"tenant 7", b"billing"],
+    &[b"tenant 7", b"billing", b"invoices"],
+    &[b"tenant 7", b"billing", b"invoices", b"epoch 3"],
+];
+
+const KEY_TREE_COMMENT: &str = r#"
+Each test is a path of labels from the root of a KeyTree, and the key at the end
+of that path. The root key is the 32 bytes 0, 1, 2, ..., 31, also given in the
+`root_key` field below. Each step derives the child key with derive_key, using
+the context string in the `context_string` field below, and key material made
+of the parent key, the length of the label as a little-endian u64, and the
+label. Keys and labels are encoded as hexadecimal.
+"#;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyTreeCases {
+    pub _comment: String,
+    pub context_string: String,
+    pub root_key: String,
+    pub cases: Vec<KeyTreeCase>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyTreeCase {
+    pub labels: Vec<String>,
+    pub key: String,
+}
+
+pub fn generate_key_tree_json() -> String {
+    let mut root_key = [0; blake3::KEY_LEN];
+    paint_test_input(&mut root_key);
+    let root = blake3::KeyTree::new(&root_key);
+    let mut cases = Vec::new();
+    for path in KEY_TREE_PATHS {
+        let mut node = root.clone();
+        for label in path.iter() {
+            node = node.child(label);
+        }
+        cases.push(KeyTreeCase {
+            labels: path.iter().map(hex::encode).collect(),
+            key: hex::encode(node.key().as_bytes()),
+        });
+    }
+
+    let mut json = serde_json::to_string_pretty(&KeyTreeCases {
+        _comment: KEY_TREE_COMMENT.trim().replace("\n", " "),
+        context_string: blake3::KEY_TREE_CONTEXT.to_string(),
+        root_key: hex::encode(root_key),
+        cases,
+    })
+    .unwrap();
+
+    // Add a trailing newline.
+    json.push('\n');
+    json
+}
+
+pub fn read_key_tree_vectors_file() -> String {
+    let key_tree_vectors_file_path = "./key_tree_vectors.json";
+    std::fs::read_to_string(key_tree_vectors_file_path)
+        .expect("failed to read key_tree_vectors.json")
+}
+
+pub fn parse_key_tree_cases() -> KeyTreeCases {
+    let json = read_key_tree_vectors_file();
+    serde_json::from_str(&json).expect("failed to parse key_tree_vectors.json")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -286,6 +360,73 @@ mod tests {
         );
     }
 
+    // Rebuild the tree from guts pieces, recursively splitting off the largest
+    // power-of-two number of chunks on the left.
+    fn guts_subtree(
+        mode: &blake3::subtree::Mode,
+        input: &[u8],
+        chunk_counter: u64,
+        is_root: bool,
+    ) -> blake3::Hash {
+        if input.len() <= CHUNK_LEN {
+            return blake3::guts::ChunkState::with_mode(mode, chunk_counter)
+                .update(input)
+                .finalize(is_root);
+        }
+        let (left, right) = guts_children(mode, input, chunk_counter);
+        blake3::guts::parent_cv_with_mode(&left, &right, is_root, mode)
+    }
+
+    fn guts_children(
+        mode: &blake3::subtree::Mode,
+        input: &[u8],
+        chunk_counter: u64,
+    ) -> (blake3::Hash, blake3::Hash) {
+        let mut left_len = CHUNK_LEN;
+        while 2 * left_len < input.len() {
+            left_len *= 2;
+        }
+        let right_counter = chunk_counter + (left_len / CHUNK_LEN) as u64;
+        (
+            guts_subtree(mode, &input[..left_len], chunk_counter, false),
+            guts_subtree(mode, &input[left_len..], right_counter, false),
+        )
+    }
+
+    // The same, but for extended output at the root.
+    fn guts_xof(mode: &blake3::subtree::Mode, input: &[u8]) -> blake3::OutputReader {
+        if input.len() <= CHUNK_LEN {
+            return blake3::guts::ChunkState::with_mode(mode, 0)
+                .update(input)
+                .finalize_xof();
+        }
+        let (left, right) = guts_children(mode, input, 0);
+        blake3::guts::parent_xof_with_mode(&left, &right, mode)
+    }
+
+    fn test_guts(
+        key: &[u8; blake3::KEY_LEN],
+        input: &[u8],
+        expected_hash: &[u8],
+        expected_keyed_hash: &[u8],
+        expected_derive_key: &[u8],
+    ) {
+        use blake3::subtree::Mode;
+
+        let modes = [
+            (Mode::hash(), expected_hash),
+            (Mode::keyed_hash(key), expected_keyed_hash),
+            (Mode::derive_key(TEST_CONTEXT), expected_derive_key),
+        ];
+        for (mode, expected) in &modes {
+            let hash = guts_subtree(mode, input, 0, true);
+            assert_eq!(&expected[..32], hash.as_bytes());
+            let mut out = vec![0; expected.len()];
+            guts_xof(mode, input).fill(&mut out);
+            assert_eq!(&expected[..], &out[..]);
+        }
+    }
+
     #[test]
     fn run_test_vectors() {
         let cases = parse_test_cases();
@@ -337,6 +478,14 @@ mod tests {
                 &expected_keyed_hash,
                 &expected_derive_key,
             );
+
+            test_guts(
+                key,
+                &input,
+                &expected_hash,
+                &expected_keyed_hash,
+                &expected_derive_key,
+            );
         }
     }
 
@@ -349,4 +498,52 @@ mod tests {
             panic!("Checked-in test_vectors.json is not up to date. Regenerate with `cargo run --bin generate > ./test_vectors.json`.");
         }
     }
+
+    // Derive each path with the reference implementation, which knows nothing
+    // about KeyTree, and with KeyTree itself.
+    #[test]
+    fn run_key_tree_vectors() {
+        let cases = parse_key_tree_cases();
+        assert_eq!(cases.context_string, blake3::KEY_TREE_CONTEXT);
+        let root_key: [u8; blake3::KEY_LEN] =
+            hex::decode(&cases.root_key).unwrap().try_into().unwrap();
+        let root = blake3::KeyTree::new(&root_key);
+        for case in &cases.cases {
+            let labels: Vec<Vec<u8>> = case
+                .labels
+                .iter()
+                .map(|l| hex::decode(l).unwrap())
+                .collect();
+            let expected = hex::decode(&case.key).unwrap();
+
+            let mut key = root_key;
+            for label in &labels {
+                let mut hasher = reference_impl::Hasher::new_derive_key(&cases.context_string);
+                hasher.update(&key);
+                hasher.update(&(label.len() as u64).to_le_bytes());
+                hasher.update(label);
+                hasher.finalize(&mut key);
+            }
+            assert_eq!(&expected[..], &key[..]);
+
+            let mut node = root.clone();
+            for label in &labels {
+                node = node.child(label);
+            }
+            assert_eq!(&expected[..], node.key().as_bytes());
+
+            let path: blake3::DerivationPath = labels.iter().collect();
+            assert_eq!(&expected[..], root.derive(&path).key().as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_checked_in_key_tree_vectors_up_to_date() {
+        // Replace Windows newlines, in case Git is configured to alter
+        // newlines when files are checked out.
+        let json = read_key_tree_vectors_file().replace("\r\n", "\n");
+        if generate_key_tree_json() != json {
+            panic!("Checked-in key_tree_vectors.json is not up to date. Regenerate with `cargo run --bin generate_key_tree > ./key_tree_vectors.json`.");
+        }
+    }
 }